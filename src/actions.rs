@@ -1,24 +1,29 @@
+use config::{OnExists, OutboundConfig, S3Config};
 use errors::*;
+use storage::{self, Storage, LocalStorage, HttpStorage, S3Storage};
 use template::PathTemplate;
 
 use std::ascii::AsciiExt;
+use std::collections::HashMap;
 use std::path::Path;
 use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
 use std::string::String;
-use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use image;
 use image::DynamicImage;
 use image::ImageFormat;
 use image::FilterType;
 use image::ImageError;
+use image::ImageDecoder;
+use image::jpeg::JPEGDecoder;
+use image::png::PNGDecoder;
 
 //use futures_pool::Sender;
-use futures::Future;
 use tokio_core::reactor::Remote as Sender;
-use hyper::{Uri, Method, StatusCode};
-use hyper::client::{Request, Client, HttpConnector};
-use multipart::client::Multipart;
+use hyper::Method;
 
 #[derive(Clone)]
 pub struct ImageData {
@@ -40,6 +45,43 @@ impl ImageData {
            })
     }
 
+    /// Decodes a probed image, taking the format from its headers instead of the file name
+    pub fn decode(image_path: &str, info: &ImageInfo, image_id: u64) -> Result<ImageData, ImageError> {
+        let file = BufReader::new(File::open(image_path)?);
+        let img = image::load(file, info.format)?;
+
+        Ok(ImageData {
+               image: img,
+               image_format: info.format,
+               id: image_id,
+           })
+    }
+
+    /// File extension of the output format
+    pub fn extension(&self) -> Result<&'static str, ActionError> {
+        match self.image_format {
+            ImageFormat::JPEG => Ok("jpg"),
+            ImageFormat::PNG => Ok("png"),
+            _ => {
+                Err(ActionError::Image(ImageError::UnsupportedError("Image format is not \
+                                                                     supported."
+                    .to_string())))
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Result<EncodedImage, ActionError> {
+        let mut bytes = Vec::new();
+        try!(self.image
+                 .save(&mut bytes, self.image_format)
+                 .map_err(|e| ActionError::Image(e)));
+        Ok(EncodedImage {
+               format: self.image_format,
+               extension: self.extension()?,
+               bytes: bytes,
+           })
+    }
+
     fn get_format(image_path: String) -> Result<ImageFormat, ImageError> {
         let path = Path::new(&image_path);
         let ext = path.extension()
@@ -58,37 +100,253 @@ impl ImageData {
     }
 }
 
+/// Extensions of every format results are encoded to
+pub const OUTPUT_EXTENSIONS: [&'static str; 2] = ["jpg", "png"];
+
+/// Image in its output format, ready to be sent
+#[derive(Clone, Debug)]
+pub struct EncodedImage {
+    pub format: ImageFormat,
+    pub extension: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+impl EncodedImage {
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            ImageFormat::PNG => "image/png",
+            ImageFormat::JPEG => "image/jpeg",
+            _ => "application/octet-stream",
+        }
+    }
+
+    /// Width and height, read from headers only
+    pub fn dimensions(&self) -> Result<(u32, u32), ImageError> {
+        let bytes = Cursor::new(&self.bytes[..]);
+        match self.format {
+            ImageFormat::JPEG => JPEGDecoder::new(bytes).dimensions(),
+            ImageFormat::PNG => PNGDecoder::new(bytes).dimensions(),
+            format => {
+                Err(ImageError::UnsupportedError(format!("Image format {:?} is not supported.",
+                                                         format)))
+            }
+        }
+    }
+}
+
+/// Image properties read from headers only, without decoding pixel data
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+}
+
+impl ImageInfo {
+    pub fn probe(image_path: &str) -> Result<ImageInfo, ImageError> {
+        let mut file = BufReader::new(File::open(image_path)?);
+        let format = {
+            let header = file.fill_buf()?;
+            image::guess_format(header)?
+        };
+
+        // animated PNGs tell their frame count before image data, JPEGs are always still
+        let (width, height, frames) = match format {
+            ImageFormat::JPEG => {
+                let (width, height) = JPEGDecoder::new(file).dimensions()?;
+                (width, height, 1)
+            }
+            ImageFormat::PNG => {
+                let frames = png_frames(&mut File::open(image_path)?)?;
+                let (width, height) = PNGDecoder::new(file).dimensions()?;
+                (width, height, frames)
+            }
+            format => {
+                return Err(ImageError::UnsupportedError(format!("Image format {:?} is not \
+                                                                 supported.",
+                                                                format)))
+            }
+        };
+
+        Ok(ImageInfo {
+               format: format,
+               width: width,
+               height: height,
+               frames: frames,
+           })
+    }
+}
+
+/// Frame count from the `acTL` chunk of an animated PNG, which comes before any image data
+fn png_frames<R: Read + Seek>(png: &mut R) -> io::Result<u32> {
+    png.seek(SeekFrom::Start(8))?;
+    loop {
+        let mut header = [0; 8];
+        png.read_exact(&mut header)?;
+        let length = u32_be(&header[0..4]);
+        match &header[4..8] {
+            b"acTL" => {
+                let mut frames = [0; 4];
+                png.read_exact(&mut frames)?;
+                return Ok(u32_be(&frames));
+            }
+            b"IDAT" | b"IEND" => return Ok(1),
+            // chunk data and its CRC
+            _ => png.seek(SeekFrom::Current(length as i64 + 4))?,
+        };
+    }
+}
+
+fn u32_be(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .take(4)
+        .fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+
+/// Time a task or an action must be finished by
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    at: Instant,
+    budget: Duration,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Deadline {
+        Deadline {
+            at: Instant::now() + budget,
+            budget: budget,
+        }
+    }
+
+    pub fn check(&self) -> Result<(), ActionError> {
+        if Instant::now() > self.at {
+            Err(ActionError::Timeout(self.budget))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The one of two deadlines coming first
+    pub fn earliest(first: Option<Deadline>, second: Option<Deadline>) -> Option<Deadline> {
+        match (first, second) {
+            (Some(first), Some(second)) => {
+                Some(if first.at <= second.at { first } else { second })
+            }
+            (first, None) => first,
+            (None, second) => second,
+        }
+    }
+}
+
+fn check_deadline(deadline: Option<Deadline>) -> Result<(), ActionError> {
+    deadline.map_or(Ok(()), |deadline| deadline.check())
+}
+
+/// Image an action passes on to the next one
+pub struct Output {
+    pub image_data: ImageData,
+    /// The same image encoded, by actions which store it
+    pub encoded: Option<EncodedImage>,
+    /// Whether the action wrote the image, which it may not where a result exists already
+    pub stored: bool,
+}
 
 #[derive(Clone)]
 pub enum ActionKind {
     Resize(Resizer),
-    Save(Saver),
-    Upload(Uploader),
+    Store(Storer),
+}
+
+/// Everything actions may need besides their parameters
+#[derive(Clone)]
+pub struct ActionContext {
+    pub executor: Sender,
+    pub outbound: Arc<OutboundConfig>,
+    pub s3: Option<Arc<S3Config>>,
+    /// Storages configured by name
+    pub storages: HashMap<String, Arc<Storage>>,
+    /// Root of `save` actions which do not give their own, if they are confined
+    pub save_root: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct Action {
     kind: ActionKind,
-    executor: Sender,
 }
 
 impl Action {
-    pub fn from_params(params: &Vec<String>, executor: Sender) -> Result<Self, ActionError> {
+    pub fn from_params(params: &Vec<String>, context: &ActionContext) -> Result<Self, ActionError> {
         let cmd = try!(params.get(0).ok_or(ActionError::Parameter));
         let kind = match cmd.as_str() {
             "resize" => Ok(ActionKind::Resize(try!(build_resizer(params)))),
-            "save" => Ok(ActionKind::Save(try!(build_saver(params)))),
-            "upload" => Ok(ActionKind::Upload(try!(build_uploader(params)))),
+            "save" => Ok(ActionKind::Store(try!(build_saver(params, context)))),
+            "upload" => Ok(ActionKind::Store(try!(build_uploader(params, context)))),
+            "s3" => Ok(ActionKind::Store(try!(build_s3_writer(params, context)))),
+            "store" => Ok(ActionKind::Store(try!(build_storer(params, context)))),
             _ => Err(ActionError::Wrong),
         }?;
-        Ok(Self { kind, executor })
+        Ok(Self { kind })
+    }
+
+    /// Runs the action, which fails if `deadline` passes. Operations in progress cannot be
+    /// interrupted, so it is checked between them.
+    pub fn run(&self,
+               image_data: &mut ImageData,
+               deadline: Option<Deadline>)
+               -> Result<Output, ActionError> {
+        check_deadline(deadline)?;
+        match &self.kind {
+            &ActionKind::Resize(ref r) => {
+                r.run(image_data, deadline).map(|image_data| {
+                                                     Output {
+                                                         image_data: image_data,
+                                                         encoded: None,
+                                                         stored: false,
+                                                     }
+                                                 })
+            }
+            &ActionKind::Store(ref s) => s.run(image_data, deadline),
+        }
+    }
+
+    /// Stores an encoded result, telling whether it was written. Transforms have nothing to
+    /// do with it.
+    pub fn store(&self,
+                 id: u64,
+                 image: &EncodedImage,
+                 deadline: Option<Deadline>)
+                 -> Result<bool, ActionError> {
+        check_deadline(deadline)?;
+        match &self.kind {
+            &ActionKind::Resize(_) => Ok(false),
+            &ActionKind::Store(ref s) => s.store(id, image, deadline),
+        }
+    }
+
+    /// Whether the action writes its input somewhere instead of transforming it
+    pub fn stores(&self) -> bool {
+        match &self.kind {
+            &ActionKind::Resize(_) => false,
+            &ActionKind::Store(_) => true,
+        }
     }
 
-    pub fn run(&self, image_data: &mut ImageData) -> Result<ImageData, ActionError> {
+    /// Deletes the result the action stored for image `id` in format of `extension`, if any
+    pub fn remove_output(&self, id: u64, extension: &str) -> Result<bool, ActionError> {
         match &self.kind {
-            &ActionKind::Resize(ref r) => r.run(image_data),
-            &ActionKind::Save(ref s) => s.run(image_data),
-            &ActionKind::Upload(ref u) => u.run(image_data, self.executor.clone()),
+            &ActionKind::Resize(_) => Ok(false),
+            &ActionKind::Store(ref s) => s.remove_output(id, extension),
+        }
+    }
+
+    /// Deletes results the action stored for image `id`, by their paths
+    pub fn purge(&self, id: u64) -> Vec<(String, Result<bool, ActionError>)> {
+        match &self.kind {
+            &ActionKind::Resize(_) => Vec::new(),
+            &ActionKind::Store(ref s) => s.remove(id),
         }
     }
 }
@@ -117,139 +375,281 @@ pub fn build_resizer(params: &Vec<String>) -> Result<Resizer, ActionError> {
 }
 
 impl Resizer {
-    pub fn run(&self, image_data: &mut ImageData) -> Result<ImageData, ActionError> {
+    pub fn run(&self,
+               image_data: &mut ImageData,
+               deadline: Option<Deadline>)
+               -> Result<ImageData, ActionError> {
+        let image = image_data
+            .image
+            .resize(self.width, self.height, self.filter);
+        // a result past the deadline is thrown away, so the job fails the same way every time
+        check_deadline(deadline)?;
         Ok(ImageData {
-               image: image_data
-                   .image
-                   .resize(self.width, self.height, self.filter),
+               image: image,
                image_format: image_data.image_format,
                id: image_data.id,
            })
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct Saver {
+/// Writes encoded images to a storage at rendered paths
+#[derive(Clone)]
+pub struct Storer {
+    storage: Arc<Storage>,
     path_template: String,
 }
 
-pub fn build_saver(params: &Vec<String>) -> Result<Saver, ActionError> {
-    let mut iter = params.iter();
-    try!(iter.next().ok_or(ActionError::Parameter));
+impl Storer {
+    fn new(storage: Arc<Storage>, path_template: &String) -> Result<Storer, ActionError> {
+        // liquid::Renderable(which is Box<Vec<Renderable>>) cannot be passed between threads
+        // So we can only check it for corectness, but cannot save it inside Sender
+        try!(PathTemplate::new(path_template.clone()).map_err(|_| ActionError::Parameter));
+        Ok(Storer {
+               storage: storage,
+               path_template: path_template.clone(),
+           })
+    }
 
-    // liquid::Renderable(which is Box<Vec<Renderable>>) cannot be passed between threads
-    // So we can only check it for corectness, but cannot save it inside Sender
-    let path_template = try!(iter.next().ok_or(ActionError::Parameter));
-    try!(PathTemplate::new(path_template.clone()).map_err(|_| ActionError::Parameter));
-    // Ok(Saver { path: "./".to_owned() })
-    Ok(Saver { path_template: path_template.clone() })
-}
+    pub fn run(&self,
+               image_data: &mut ImageData,
+               deadline: Option<Deadline>)
+               -> Result<Output, ActionError> {
+        let image = image_data.encode()?;
+        let stored = self.store(image_data.id, &image, deadline)?;
+        Ok(Output {
+               image_data: (*image_data).clone(),
+               encoded: Some(image),
+               stored: stored,
+           })
+    }
 
-impl Saver {
-    pub fn run(&self, image_data: &mut ImageData) -> Result<ImageData, ActionError> {
+    /// Writes an image encoded already, like a cached result, telling whether it did
+    pub fn store(&self,
+                 id: u64,
+                 image: &EncodedImage,
+                 deadline: Option<Deadline>)
+                 -> Result<bool, ActionError> {
         let template = try!(PathTemplate::new(self.path_template.clone())
             .map_err(|_| ActionError::Parameter));
 
-        let extension = try!(match image_data.image_format {
-                                 ImageFormat::JPEG => Ok("jpg"),
-                                 ImageFormat::PNG => Ok("png"),
-                                 _ => {
-                Err(ActionError::Image(ImageError::UnsupportedError("Image format is not \
-                                                                     supported."
-                    .to_string())))
-            }
-                             });
-
         let path = try!(template
-                            .render(image_data.id, extension.to_owned())
+                            .render(id, image.extension.to_owned())
                             .map_err(|e| ActionError::BadTemplate(e)));
-        info!("SAVING to {:?}", path);
-        let mut file = try!(File::create(path).map_err(|e| ActionError::Io(e)));
+        // nothing is written once the deadline passed, so there is nothing to clean up
+        check_deadline(deadline)?;
+        self.storage.store(id, &path, image)
+    }
 
-        try!(image_data
-                 .image
-                 .save(&mut file, image_data.image_format)
-                 .map_err(|e| ActionError::Image(e)));
-        Ok((*image_data).clone())
+    pub fn remove_output(&self, id: u64, extension: &str) -> Result<bool, ActionError> {
+        let path = PathTemplate::new(self.path_template.clone())
+            .and_then(|template| template.render(id, extension.to_owned()))
+            .map_err(|e| ActionError::BadTemplate(e))?;
+        self.storage.remove(id, extension, &path)
+    }
+
+    /// Results keep the format of their source, so paths with every output extension are tried
+    pub fn remove(&self, id: u64) -> Vec<(String, Result<bool, ActionError>)> {
+        let template = match PathTemplate::new(self.path_template.clone()) {
+            Ok(template) => template,
+            Err(e) => return vec![(self.path_template.clone(), Err(ActionError::BadTemplate(e)))],
+        };
+        let mut removed: Vec<(String, Result<bool, ActionError>)> = Vec::new();
+        for extension in OUTPUT_EXTENSIONS.iter() {
+            match template.render(id, extension.to_string()) {
+                Ok(path) => {
+                    if removed.iter().any(|&(ref removed, _)| removed == &path) {
+                        continue;
+                    }
+                    let result = self.storage.remove(id, extension, &path);
+                    removed.push((path, result));
+                }
+                Err(e) => {
+                    removed.push((self.path_template.clone(), Err(ActionError::BadTemplate(e))))
+                }
+            }
+        }
+        removed
     }
 }
 
+/// Takes storage name and path template
+pub fn build_storer(params: &Vec<String>, context: &ActionContext) -> Result<Storer, ActionError> {
+    let mut iter = params.iter();
+    try!(iter.next().ok_or(ActionError::Parameter));
 
-#[derive(Clone, Debug)]
-pub struct Uploader {
-    path_template: String,
+    let name = try!(iter.next().ok_or(ActionError::Parameter));
+    let storage = try!(context
+                           .storages
+                           .get(name)
+                           .ok_or_else(|| ActionError::UnknownStorage(name.clone())));
+    let path_template = try!(iter.next().ok_or(ActionError::Parameter));
+    Storer::new(storage.clone(), path_template)
 }
 
-pub fn build_uploader(params: &Vec<String>) -> Result<Uploader, ActionError> {
+/// Takes file path template followed by options: `root=<dir>` the path cannot leave, instead of
+/// the configured `save_root`, `mode=<octal>` of the file, `dir_mode=<octal>` of created directories
+/// and `exists=overwrite|skip|fail`. Without either root paths are taken as they are.
+pub fn build_saver(params: &Vec<String>, context: &ActionContext) -> Result<Storer, ActionError> {
     let mut iter = params.iter();
     try!(iter.next().ok_or(ActionError::Parameter));
 
     let path_template = try!(iter.next().ok_or(ActionError::Parameter));
-    try!(PathTemplate::new(path_template.clone()).map_err(|_| ActionError::Parameter));
 
-    Ok(Uploader { path_template: path_template.clone() })
+    let mut root = context.save_root.as_ref().map_or("", |root| root.as_str());
+    let mut file_mode = None;
+    let mut dir_mode = None;
+    let mut on_exists = OnExists::Overwrite;
+    for option in iter {
+        let mut parts = option.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("root"), Some(dir)) => root = dir,
+            (Some("mode"), Some(mode)) => file_mode = Some(try!(storage::parse_mode(mode))),
+            (Some("dir_mode"), Some(mode)) => dir_mode = Some(try!(storage::parse_mode(mode))),
+            (Some("exists"), Some("overwrite")) => on_exists = OnExists::Overwrite,
+            (Some("exists"), Some("skip")) => on_exists = OnExists::Skip,
+            (Some("exists"), Some("fail")) => on_exists = OnExists::Fail,
+            _ => return Err(ActionError::Parameter),
+        }
+    }
+
+    let mut local = LocalStorage::new(root).on_exists(on_exists);
+    if let Some(mode) = file_mode {
+        local = local.file_mode(mode);
+    }
+    if let Some(mode) = dir_mode {
+        local = local.dir_mode(mode);
+    }
+    Storer::new(Arc::new(local), path_template)
 }
 
-impl Uploader {
-    pub fn run(&self,
-               image_data: &mut ImageData,
-               executor: Sender)
-               -> Result<ImageData, ActionError> {
-        let template = try!(PathTemplate::new(self.path_template.clone())
-            .map_err(|_| ActionError::Parameter));
+/// Takes url template followed by options: `method=POST|PUT`, `body=raw|multipart`,
+/// `field=<form field name>` and any number of `header=<Name>: <value template>`
+pub fn build_uploader(params: &Vec<String>,
+                      context: &ActionContext)
+                      -> Result<Storer, ActionError> {
+    let mut iter = params.iter();
+    try!(iter.next().ok_or(ActionError::Parameter));
 
-        let path = try!(template
-                            .render(image_data.id, "jpg".to_owned())
-                            .map_err(|e| ActionError::BadTemplate(e)));
+    let path_template = try!(iter.next().ok_or(ActionError::Parameter));
+
+    let mut method = Method::Post;
+    let mut multipart = false;
+    let mut field = "file".to_string();
+    let mut headers = Vec::new();
+    for option in iter {
+        let mut parts = option.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("method"), Some("POST")) => method = Method::Post,
+            (Some("method"), Some("PUT")) => method = Method::Put,
+            (Some("body"), Some("raw")) => multipart = false,
+            (Some("body"), Some("multipart")) => multipart = true,
+            (Some("field"), Some(name)) if !name.is_empty() => field = name.to_string(),
+            (Some("header"), Some(header)) => {
+                let mut header = header.splitn(2, ':');
+                let name = try!(header.next().ok_or(ActionError::Parameter)).trim();
+                let value = try!(header.next().ok_or(ActionError::Parameter)).trim();
+                if name.is_empty() {
+                    return Err(ActionError::Parameter);
+                }
+                headers.push((name.to_string(), value.to_string()));
+            }
+            _ => return Err(ActionError::Parameter),
+        }
+    }
 
-        // let path = &self.path_template;
+    // the whole url is templated, so the storage has none of its own
+    let storage = try!(HttpStorage::new(String::new(),
+                                        method,
+                                        if multipart { Some(field) } else { None },
+                                        headers,
+                                        context.executor.clone(),
+                                        context.outbound.clone()));
+    Storer::new(Arc::new(storage), path_template)
+}
 
-        let uri = try!(Uri::from_str(&path).map_err(|e| ActionError::UrlParse(e)));
-        let uri_log = format!("{:?}", &uri);
-        let mut body = Vec::new();
-        try!(image_data
-                 .image
-                 .save(&mut body, image_data.image_format)
-                 .map_err(|e| ActionError::Image(e)));
-        executor.spawn(move |handle| {
-            let client = Client::configure()
-                .connector(HttpConnector::new(1, &handle))
-                .build(&handle);
-            let mut request = Request::new(Method::Post, uri);
-            request.set_body(body);
-            let future = client
-                .request(request)
-                .and_then(|ref response| {
-                    if let StatusCode::Ok = response.status() {
-                        debug!("external upload successful"; "uri"=>uri_log);
-                    } else {
-                        debug!("external upload failed"; "response"=>format!("{:?}", &response));
-                    };
-                    Ok(())
-                })
-                .then(|_| Ok(())); // TODO log error
-            future
-
-        });
-
-        //Err(ActionError::Run)
-        //let mut multipart = try!(Multipart::from_request(request)
-        //.map_err(|e| ActionError::HyperRequestError(e)));
-
-        //// image_data.image.save(&mut file, ImageFormat::JPEG);
-        //// /        image_data.image.save(&mut buffer, ImageFormat::JPEG);
-
-        //// let mut file = try!(File::open(&file_path).map_err(|e| ActionError::Io(e)));
-        //let buf = image_data.image.raw_pixels();
-        //multipart
-        //.write_stream("file", &mut buf.as_slice(), None, None)
-        //.unwrap();
-        ////        multipart.write_stream("file", &mut buffer, None, None).unwrap();
-
-        //try!(multipart
-        //.send()
-        //.map_err(|e| ActionError::HyperRequestError(e)));
-
-        Ok((*image_data).clone())
+/// Takes object key template followed by options: `bucket=<name>` instead of the configured one,
+/// `content_type=<type>` instead of the one of output format and `cache_control=<value>`
+pub fn build_s3_writer(params: &Vec<String>,
+                       context: &ActionContext)
+                       -> Result<Storer, ActionError> {
+    let config = try!(context.s3.clone().ok_or(ActionError::Unconfigured("s3")));
+    let mut iter = params.iter();
+    try!(iter.next().ok_or(ActionError::Parameter));
+
+    let key_template = try!(iter.next().ok_or(ActionError::Parameter));
+
+    let mut storage = S3Storage::new(config, context.executor.clone(), context.outbound.clone());
+    for option in iter {
+        let mut parts = option.splitn(2, '=');
+        storage = match (parts.next(), parts.next()) {
+            (Some("bucket"), Some(bucket)) if !bucket.is_empty() => {
+                storage.bucket(bucket.to_string())
+            }
+            (Some("content_type"), Some(value)) => storage.content_type(value.to_string()),
+            (Some("cache_control"), Some(value)) => storage.cache_control(value.to_string()),
+            _ => return Err(ActionError::Parameter),
+        };
+    }
+    Storer::new(Arc::new(storage), key_template)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::thread;
+
+    /// PNG of a single pixel with chunks inserted after its header
+    fn png(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(1, 1)
+            .save(&mut bytes, ImageFormat::PNG)
+            .unwrap();
+        // signature and IHDR chunk of 13 bytes
+        let mut png = bytes[..8 + 12 + 13].to_vec();
+        for &(kind, data) in chunks {
+            png.extend_from_slice(&[0, 0, 0, data.len() as u8]);
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            // the CRC is not checked when counting frames
+            png.extend_from_slice(&[0; 4]);
+        }
+        png.extend_from_slice(&bytes[8 + 12 + 13..]);
+        png
+    }
+
+    #[test]
+    fn test_png_frames() {
+        let text = (&b"tEXt"[..], &b"a\0b"[..]);
+        let still = png(&[text]);
+        assert_eq!(png_frames(&mut Cursor::new(still)).unwrap(), 1);
+
+        let animated = png(&[text, (&b"acTL"[..], &[0u8, 0, 0, 12, 0, 0, 0, 0][..])]);
+        assert_eq!(png_frames(&mut Cursor::new(animated)).unwrap(), 12);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let memory = Arc::new(storage::MemoryStorage::default());
+        let storer = Storer::new(memory.clone(), &"images/{{image_id}}.{{ext}}".to_string());
+        let action = Action { kind: ActionKind::Store(storer.unwrap()) };
+        let mut image_data = ImageData {
+            image: DynamicImage::new_rgb8(4, 4),
+            image_format: ImageFormat::PNG,
+            id: 7,
+        };
+
+        let deadline = Deadline::after(Duration::from_millis(0));
+        thread::sleep(Duration::from_millis(1));
+        match action.run(&mut image_data, Some(deadline)) {
+            Err(ActionError::Timeout(_)) => (),
+            _ => panic!("deadline not enforced"),
+        }
+        assert!(memory.get("images/7.png").is_none());
+
+        let deadline = Deadline::after(Duration::from_secs(60));
+        let output = action.run(&mut image_data, Some(deadline)).unwrap();
+        assert!(output.stored);
+        assert!(memory.get("images/7.png").is_some());
     }
 }
@@ -0,0 +1,322 @@
+use actions::EncodedImage;
+use config::CacheConfig;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use image::ImageFormat;
+
+/// Cache key of a task result for an image, `digest` covering everything else the result depends on
+pub fn key(image_id: u64, digest: &str) -> String {
+    format!("{}-{}", image_id, digest)
+}
+
+/// Makes names of temporary files unique among concurrent writes of the same key
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Encoded task results on local disk, evicted least recently used first
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Option<Duration>,
+    index: Mutex<Index>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub entries: usize,
+    pub size: u64,
+}
+
+struct Entry {
+    path: PathBuf,
+    format: ImageFormat,
+    size: u64,
+    created: SystemTime,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    // keys by the tick of their last use, so the first one is evicted first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+impl Index {
+    fn insert(&mut self, key: String, mut entry: Entry) -> Option<Entry> {
+        let old = self.remove(&key);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.size += entry.size;
+        self.lru.insert(entry.used, key.clone());
+        self.entries.insert(key, entry);
+        old
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = self.tick;
+            self.lru.insert(entry.used, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key);
+        if let Some(ref entry) = entry {
+            self.lru.remove(&entry.used);
+            self.size -= entry.size;
+        }
+        entry
+    }
+
+    /// Drops least recently used entries until total size fits
+    fn evict(&mut self, max_size: u64) -> Vec<Entry> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let key = match self.lru.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            evicted.extend(self.remove(&key));
+        }
+        evicted
+    }
+}
+
+impl Cache {
+    /// Opens cache directory, picking up results left from previous runs
+    pub fn open(config: &CacheConfig) -> Result<Cache, io::Error> {
+        let dir = PathBuf::from(&config.dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut found = Vec::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            let format = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("jpg") => ImageFormat::JPEG,
+                Some("png") => ImageFormat::PNG,
+                // leftovers of interrupted writes
+                _ => {
+                    fs::remove_file(&path).ok();
+                    continue;
+                }
+            };
+            let key = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+            let metadata = fs::metadata(&path)?;
+            let created = metadata.modified().unwrap_or(UNIX_EPOCH);
+            found.push((key,
+                        Entry {
+                            path: path,
+                            format: format,
+                            size: metadata.len(),
+                            created: created,
+                            used: 0,
+                        }));
+        }
+
+        // without better knowledge the oldest files are the least recently used
+        found.sort_by_key(|&(_, ref entry)| entry.created);
+        let mut index = Index::default();
+        for (key, entry) in found {
+            index.insert(key, entry);
+        }
+        for entry in index.evict(config.max_size) {
+            fs::remove_file(&entry.path).ok();
+        }
+
+        Ok(Cache {
+               dir: dir,
+               max_size: config.max_size,
+               max_age: config.max_age.map(Duration::from_secs),
+               index: Mutex::new(index),
+               hits: AtomicUsize::new(0),
+               misses: AtomicUsize::new(0),
+           })
+    }
+
+    pub fn get(&self, key: &str) -> Option<EncodedImage> {
+        let image = self.lookup(key);
+        if image.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        image
+    }
+
+    fn lookup(&self, key: &str) -> Option<EncodedImage> {
+        let (path, format) = {
+            let mut index = self.index.lock().unwrap();
+            let expired = match index.entries.get(key) {
+                Some(entry) => self.expired(entry),
+                None => return None,
+            };
+            if expired {
+                if let Some(entry) = index.remove(key) {
+                    fs::remove_file(&entry.path).ok();
+                }
+                return None;
+            }
+            index.touch(key);
+            let entry = &index.entries[key];
+            (entry.path.clone(), entry.format)
+        };
+
+        let mut bytes = Vec::new();
+        match File::open(&path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+            Ok(_) => {
+                Some(EncodedImage {
+                         format: format,
+                         extension: extension(format),
+                         bytes: bytes,
+                     })
+            }
+            Err(e) => {
+                warn!("cached result unreadable {}", e);
+                self.index.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    fn expired(&self, entry: &Entry) -> bool {
+        match self.max_age {
+            Some(max_age) => {
+                entry
+                    .created
+                    .elapsed()
+                    .map(|age| age > max_age)
+                    .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    pub fn put(&self, key: &str, image: &EncodedImage) {
+        let path = self.dir.join(format!("{}.{}", key, extension(image.format)));
+        // written aside and renamed, so readers never see partial results
+        let temp = self.dir.join(format!("{}.{}.{}.tmp",
+                                         key,
+                                         process::id(),
+                                         TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let written = File::create(&temp)
+            .and_then(|mut file| file.write_all(&image.bytes))
+            .and_then(|_| fs::rename(&temp, &path));
+        if let Err(e) = written {
+            warn!("could not cache result {}", e);
+            fs::remove_file(&temp).ok();
+            return;
+        }
+
+        let entry = Entry {
+            path: path.clone(),
+            format: image.format,
+            size: image.bytes.len() as u64,
+            created: SystemTime::now(),
+            used: 0,
+        };
+        let (old, evicted) = {
+            let mut index = self.index.lock().unwrap();
+            let old = index.insert(key.to_string(), entry);
+            (old, index.evict(self.max_size))
+        };
+        if let Some(old) = old {
+            if old.path != path {
+                fs::remove_file(&old.path).ok();
+            }
+        }
+        for entry in evicted {
+            fs::remove_file(&entry.path).ok();
+        }
+    }
+
+    /// Drops every cached result of an image, returning how many there were
+    pub fn purge(&self, image_id: u64) -> usize {
+        let prefix = format!("{}-", image_id);
+        let purged = {
+            let mut index = self.index.lock().unwrap();
+            let keys: Vec<String> = index
+                .entries
+                .keys()
+                .filter(|key| key.starts_with(&prefix))
+                .cloned()
+                .collect();
+            keys.iter()
+                .filter_map(|key| index.remove(key))
+                .collect::<Vec<_>>()
+        };
+        for entry in purged.iter() {
+            fs::remove_file(&entry.path).ok();
+        }
+        purged.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let index = self.index.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: index.entries.len(),
+            size: index.size,
+        }
+    }
+}
+
+fn extension(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::PNG => "png",
+        _ => "jpg",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    fn entry(size: u64) -> Entry {
+        Entry {
+            path: PathBuf::new(),
+            format: ImageFormat::PNG,
+            size: size,
+            created: SystemTime::now(),
+            used: 0,
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut index = Index::default();
+        index.insert("a".to_string(), entry(10));
+        index.insert("b".to_string(), entry(10));
+        index.insert("c".to_string(), entry(10));
+        index.touch("a");
+        assert_eq!(index.size, 30);
+
+        let evicted = index.evict(20);
+        assert_eq!(evicted.len(), 1);
+        assert!(!index.entries.contains_key("b"));
+        assert_eq!(index.size, 20);
+
+        index.insert("c".to_string(), entry(5));
+        assert_eq!(index.size, 15);
+        assert_eq!(index.lru.values().collect::<Vec<_>>(), vec!["a", "c"]);
+    }
+}
@@ -0,0 +1,54 @@
+use config::{CallbackConfig, OutboundConfig};
+use errors::OutboundError;
+use outbound::{self, OutboundFuture};
+use signing;
+
+use futures::Future;
+use futures::future::err;
+use tokio_core::reactor::Handle;
+use hyper::{Method, Uri};
+use hyper::header::{ContentLength, ContentType};
+use hyper::client::Request;
+
+/// Header with `sha256=` and the hex encoded HMAC-SHA256 of the report, keyed with callback key
+pub const SIGNATURE_HEADER: &'static str = "X-Gravure-Signature";
+
+/// Checks a callback url given by a client against outbound policy
+pub fn check_url(outbound: &OutboundConfig, url: &str) -> Result<Uri, OutboundError> {
+    let uri = url.parse().map_err(|e| OutboundError::UrlParse(e))?;
+    outbound::check_uri(outbound, &uri)?;
+    Ok(uri)
+}
+
+/// Posts a JSON report to `url`, retrying as callback config says
+pub fn post(handle: &Handle,
+            outbound: &OutboundConfig,
+            config: &CallbackConfig,
+            url: &str,
+            body: Vec<u8>)
+            -> OutboundFuture<()> {
+    let uri = match check_url(outbound, url) {
+        Ok(uri) => uri,
+        Err(e) => return Box::new(err(e)),
+    };
+    let signature = config
+        .key
+        .as_ref()
+        .map(|key| format!("sha256={}", signing::sign_body(key, &body)));
+    let mut outbound = outbound.clone();
+    outbound.retry = config.retry.clone();
+
+    let request = move || {
+        let mut request = Request::new(Method::Post, uri.clone());
+        request.headers_mut().set(ContentType::json());
+        request.headers_mut().set(ContentLength(body.len() as u64));
+        if let Some(ref signature) = signature {
+            request
+                .headers_mut()
+                .set_raw(SIGNATURE_HEADER, signature.clone());
+        }
+        request.set_body(body.clone());
+        request
+    };
+    Box::new(outbound::send_with_retries(handle, &outbound, request).map(|_| ()))
+}
@@ -1,19 +1,394 @@
 use errors::*;
 use std::collections::HashMap;
+use std::sync::Arc;
 //use futures_pool::Sender;
 use tokio_core::reactor::Remote as Sender;
 
 use actions::*;
+use storage;
+use template::PathTemplate;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub presets: HashMap<String, Preset>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub origin: Option<Origin>,
+    #[serde(default)]
+    pub signing: Option<Signing>,
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    /// Named storages `store` actions write to
+    #[serde(default)]
+    pub storages: HashMap<String, StorageConfig>,
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    #[serde(default)]
+    pub callbacks: CallbackConfig,
+    #[serde(default)]
+    pub journal: Option<JournalConfig>,
+    /// Worker pools by name, sharing the worker threads
+    #[serde(default)]
+    pub queues: HashMap<String, QueueConfig>,
+    #[serde(default)]
+    pub backlog: BacklogConfig,
+    /// Directory `save` actions without a `root=` option cannot leave, unconfined if unset
+    #[serde(default)]
+    pub save_root: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Preset {
     pub name: String,
     pub tasks: Vec<Task>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub origin: Option<Origin>,
+    /// Url job reports are posted to, unless the request gives its own
+    #[serde(default)]
+    pub callback: Option<String>,
+    /// Queue jobs are run on, unless the request names its own
+    #[serde(default)]
+    pub queue: Option<String>,
+}
+
+/// Settings and policy of every HTTP request Gravure makes itself
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct OutboundConfig {
+    /// Seconds for a whole request to complete
+    pub timeout: Option<u64>,
+    pub max_redirects: u32,
+    pub allowed_schemes: Vec<String>,
+    /// Host names or `*.domain` patterns, any host is allowed if empty
+    pub allowed_hosts: Vec<String>,
+    /// Allow connecting to private, loopback and link local addresses
+    pub allow_internal: bool,
+    /// Let storages, uploads and origins of this config reach internal addresses regardless of
+    /// `allow_internal`, which then only holds urls given by clients
+    pub allow_internal_configured: bool,
+    pub retry: RetryConfig,
+    pub pool: PoolConfig,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        OutboundConfig {
+            timeout: Some(30),
+            max_redirects: 5,
+            allowed_schemes: vec!["http".to_string()],
+            allowed_hosts: Vec::new(),
+            allow_internal: false,
+            allow_internal_configured: true,
+            retry: RetryConfig::default(),
+            pool: PoolConfig::default(),
+        }
+    }
+}
+
+/// Connections of the client shared by outbound requests of an event loop, timeouts in seconds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PoolConfig {
+    pub keep_alive: bool,
+    /// Idle connections are closed after this
+    pub idle_timeout: Option<u64>,
+    /// Requests in flight in total, new ones wait for a free slot. Idle connections kept alive
+    /// are not counted.
+    pub max_in_flight: Option<usize>,
+    /// Requests in flight to a single host and port
+    pub max_per_host: Option<usize>,
+    pub connect_timeout: Option<u64>,
+    /// Longest wait for the response or for more of its body, while a request is in flight
+    pub read_timeout: Option<u64>,
+    /// Threads resolving host names, lookups beyond them wait
+    pub resolver_threads: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            keep_alive: true,
+            idle_timeout: Some(90),
+            max_in_flight: Some(100),
+            max_per_host: Some(10),
+            connect_timeout: Some(5),
+            read_timeout: Some(30),
+            resolver_threads: 4,
+        }
+    }
+}
+
+/// Exponential backoff of failed outbound requests, delays in milliseconds
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Total number of attempts, the first one included
+    pub attempts: u32,
+    pub initial_delay: u64,
+    pub max_delay: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 3,
+            initial_delay: 200,
+            max_delay: 5000,
+        }
+    }
+}
+
+/// Status of jobs kept in memory for clients to poll
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// Oldest jobs are forgotten first beyond this count
+    pub max_jobs: usize,
+    /// Seconds a job is kept after it was created
+    pub ttl: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            max_jobs: 10000,
+            ttl: 3600,
+        }
+    }
+}
+
+/// Posting of job reports to callback urls
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CallbackConfig {
+    /// Reports are signed with it when set, so receivers can check they come from Gravure
+    pub key: Option<String>,
+    pub retry: RetryConfig,
+}
+
+impl Default for CallbackConfig {
+    fn default() -> Self {
+        CallbackConfig {
+            key: None,
+            retry: RetryConfig {
+                attempts: 5,
+                initial_delay: 1000,
+                max_delay: 60000,
+            },
+        }
+    }
+}
+
+/// Queue jobs are run on when neither preset nor request names one
+pub const DEFAULT_QUEUE: &'static str = "default";
+
+/// Worker pool of its own, so jobs of other queues cannot starve its jobs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueueConfig {
+    /// Part of worker threads, relative to shares of other queues, at least 1
+    pub share: u32,
+}
+
+/// Tasks accepted at once, requests beyond them are answered 503
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BacklogConfig {
+    /// Tasks queued or running on all queues, every task of a preset counts
+    pub max_tasks: Option<usize>,
+    /// Seconds rejected clients are told to wait before retrying
+    pub retry_after: u64,
+}
+
+impl Default for BacklogConfig {
+    fn default() -> Self {
+        BacklogConfig {
+            max_tasks: Some(1000),
+            retry_after: 5,
+        }
+    }
+}
+
+/// Log of accepted jobs replayed on startup
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalConfig {
+    pub path: String,
+}
+
+/// Disk cache of task results
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheConfig {
+    pub dir: String,
+    /// Total size of cached results in bytes
+    pub max_size: u64,
+    /// Seconds after which a cached result is not used anymore
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+/// S3 compatible object storage, addressed in path style as MinIO expects
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct S3Config {
+    /// Base url like `http://127.0.0.1:9000`
+    pub endpoint: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// Bucket of `s3` actions not naming their own
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Objects larger than this are sent in parts of this size, 5MiB at least
+    #[serde(default = "default_part_size")]
+    pub part_size: usize,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_part_size() -> usize {
+    8 * 1024 * 1024
+}
+
+/// Storage backend, paths rendered by actions are relative to its root or url
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local {
+        root: String,
+        /// Octal permissions of written files, like `"644"`
+        #[serde(default)]
+        file_mode: Option<String>,
+        /// Octal permissions of created directories
+        #[serde(default)]
+        dir_mode: Option<String>,
+        #[serde(default)]
+        on_exists: OnExists,
+    },
+    Http {
+        url: String,
+        #[serde(default = "default_http_method")]
+        method: String,
+        /// Send the image as a `multipart/form-data` file field instead of the raw body
+        #[serde(default)]
+        multipart: bool,
+        #[serde(default = "default_form_field")]
+        field: String,
+        /// Header value templates
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    Webdav {
+        url: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    S3(S3Config),
+    Memory,
+}
+
+/// What local saves do when the file is there already
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExists {
+    Overwrite,
+    Skip,
+    Fail,
+}
+
+impl Default for OnExists {
+    fn default() -> Self {
+        OnExists::Overwrite
+    }
+}
+
+fn default_http_method() -> String {
+    "POST".to_string()
+}
+
+fn default_form_field() -> String {
+    "file".to_string()
+}
+
+/// Keys on-the-fly urls must be signed with, any of them is accepted to allow rotation
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Signing {
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub require_expiry: bool,
+}
+
+/// Where on-the-fly requests take source images from, both templated by image id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Origin {
+    Local { path: String },
+    Http { url: String },
+}
+
+impl Origin {
+    pub fn template(&self) -> &str {
+        match self {
+            &Origin::Local { ref path } => path,
+            &Origin::Http { ref url } => url,
+        }
+    }
+}
+
+/// Bounds on source images, checked against image headers before decoding
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Limits {
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+    pub max_pixels: Option<u64>,
+    pub max_frames: Option<u64>,
+    /// Maximum size of an uploaded request body in bytes
+    pub max_body_size: Option<u64>,
+    /// Milliseconds every task may take to decode its source and run its actions
+    pub task_timeout: Option<u64>,
+    /// Milliseconds every single action may take
+    pub action_timeout: Option<u64>,
+}
+
+impl Limits {
+    /// Takes every limit not set here from `defaults`
+    pub fn or(&self, defaults: &Limits) -> Limits {
+        Limits {
+            max_width: self.max_width.or(defaults.max_width),
+            max_height: self.max_height.or(defaults.max_height),
+            max_pixels: self.max_pixels.or(defaults.max_pixels),
+            max_frames: self.max_frames.or(defaults.max_frames),
+            max_body_size: self.max_body_size.or(defaults.max_body_size),
+            task_timeout: self.task_timeout.or(defaults.task_timeout),
+            action_timeout: self.action_timeout.or(defaults.action_timeout),
+        }
+    }
+
+    pub fn check(&self, info: &ImageInfo) -> Result<(), JobError> {
+        let width = info.width as u64;
+        let height = info.height as u64;
+        check_limit("width", width, self.max_width)?;
+        check_limit("height", height, self.max_height)?;
+        check_limit("pixels", width * height, self.max_pixels)?;
+        check_limit("frames", info.frames as u64, self.max_frames)?;
+        Ok(())
+    }
+}
+
+fn check_limit(what: &'static str, value: u64, limit: Option<u64>) -> Result<(), JobError> {
+    match limit {
+        Some(limit) if value > limit => Err(JobError::TooLarge(what, value, limit)),
+        _ => Ok(()),
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -31,10 +406,9 @@ pub struct Task {
 }
 
 impl Task {
-    fn init(&mut self, executor: Sender) -> Result<(), ActionError> {
+    fn init(&mut self, context: &ActionContext) -> Result<(), ActionError> {
         for params in &self.actions_raw {
-            params.get(0).ok_or(ActionError::Parameter)?;
-            let action = Action::from_params(params, executor.clone()).unwrap();
+            let action = Action::from_params(params, context)?;
             self.actions.push(action);
         }
         Ok(())
@@ -43,10 +417,71 @@ impl Task {
 
 // TODO: Enforce init somehow
 impl Config {
+    /// Preset limits with unset values taken from global ones
+    pub fn limits(&self, preset: &Preset) -> Limits {
+        preset.limits.or(&self.limits)
+    }
+
+    /// Preset origin, falling back to the global one
+    pub fn origin<'a>(&'a self, preset: &'a Preset) -> Option<&'a Origin> {
+        preset.origin.as_ref().or(self.origin.as_ref())
+    }
+
+    /// Outbound policy of requests to urls written in this config rather than given by clients
+    pub fn configured_outbound(&self) -> OutboundConfig {
+        let mut outbound = self.outbound.clone();
+        if outbound.allow_internal_configured {
+            outbound.allow_internal = true;
+        }
+        outbound
+    }
+
     pub fn init(&mut self, executor: Sender) -> Result<(), ConfigError> {
+        {
+            let origins = self.presets
+                .values()
+                .filter_map(|preset| preset.origin.as_ref())
+                .chain(self.origin.as_ref());
+            let mut origins = origins.peekable();
+            // anyone could have any image processed through unsigned on-the-fly urls
+            let signed = self.signing.as_ref().map_or(false, |signing| !signing.keys.is_empty());
+            if origins.peek().is_some() && !signed {
+                return Err(ConfigError::UnsignedOrigin);
+            }
+            for origin in origins {
+                PathTemplate::new(origin.template().to_string())
+                    .map_err(ConfigError::Template)?;
+            }
+        }
+        for (name, queue) in &self.queues {
+            if queue.share == 0 {
+                return Err(ConfigError::ZeroShare(name.clone()));
+            }
+        }
+        for preset in self.presets.values() {
+            if let Some(ref queue) = preset.queue {
+                if queue != DEFAULT_QUEUE && !self.queues.contains_key(queue) {
+                    return Err(ConfigError::UnknownQueue(queue.clone()));
+                }
+            }
+        }
+        let outbound = Arc::new(self.configured_outbound());
+        let mut storages = HashMap::new();
+        for (name, storage_config) in &self.storages {
+            let storage = storage::build(storage_config, &executor, &outbound)
+                .map_err(|e| ConfigError::Storage(name.clone(), e))?;
+            storages.insert(name.clone(), storage);
+        }
+        let context = ActionContext {
+            executor: executor,
+            outbound: outbound,
+            s3: self.s3.clone().map(Arc::new),
+            storages: storages,
+            save_root: self.save_root.clone(),
+        };
         for (_, preset) in &mut self.presets {
             for task in &mut preset.tasks {
-                task.init(executor.clone()).map_err(ConfigError::Init)?;
+                task.init(&context).map_err(ConfigError::Init)?;
             }
         }
         Ok(())
@@ -57,6 +492,9 @@ impl Config {
 #[cfg(test)]
 mod tests {
     use config::*;
+    use actions::ImageInfo;
+    use errors::JobError;
+    use image::ImageFormat;
     use serde_json::from_str;
 
     #[test]
@@ -94,4 +532,35 @@ mod tests {
         let tasks = &config.presets.get("preset1").unwrap().tasks;
         assert_eq!(tasks.len(), 2);
     }
+
+    #[test]
+    fn check_limits() {
+        let global = Limits {
+            max_width: Some(1000),
+            max_pixels: Some(500_000),
+            ..Limits::default()
+        };
+        let preset = Limits {
+            max_width: Some(2000),
+            ..Limits::default()
+        };
+        let limits = preset.or(&global);
+        assert_eq!(limits.max_width, Some(2000));
+        assert_eq!(limits.max_pixels, Some(500_000));
+
+        let mut info = ImageInfo {
+            format: ImageFormat::PNG,
+            width: 1500,
+            height: 300,
+            frames: 1,
+        };
+        assert!(limits.check(&info).is_ok());
+        assert!(global.check(&info).is_err());
+
+        info.height = 400;
+        match limits.check(&info) {
+            Err(JobError::TooLarge("pixels", 600_000, 500_000)) => (),
+            _ => panic!("pixel limit not enforced"),
+        }
+    }
 }
@@ -1,8 +1,12 @@
+use std::error::Error;
 use std::io::Error as IoError;
+use std::time::Duration;
 use liquid::Error as LiquidError;
 use image::ImageError;
 use hyper::Error as HyperError;
+use hyper::StatusCode;
 use hyper::error::UriError as UriParseError;
+use serde_json::Error as JsonError;
 
 quick_error! {
     #[derive(Debug)]
@@ -16,6 +20,26 @@ quick_error! {
         Wrong {
             description("Wrong action")
         }
+        Unconfigured(what: &'static str) {
+            description("action is not configured")
+            display("{} is not configured", what)
+        }
+        UnknownStorage(name: String) {
+            description("unknown storage")
+            display("unknown storage {}", name)
+        }
+        PathEscapesRoot(path: String) {
+            description("path escapes storage root")
+            display("path {} escapes storage root", path)
+        }
+        Exists(path: String) {
+            description("file exists")
+            display("{} exists already", path)
+        }
+        Timeout(budget: Duration) {
+            description("time budget exceeded")
+            display("time budget of {:?} exceeded", budget)
+        }
 
         BadTemplate(e: TemplateError) {
             cause(e)
@@ -33,6 +57,11 @@ quick_error! {
         HyperRequestError(e: HyperError) {
             cause(e)
         }
+        Outbound(e: OutboundError) {
+            cause(e)
+            description(e.description())
+            display("{}", e)
+        }
     }
 }
 
@@ -51,6 +80,10 @@ quick_error! {
             cause(e)
             description("action failed")
         }
+        TooLarge(what: &'static str, value: u64, limit: u64) {
+            description("image exceeds limits")
+            display("image {} {} exceeds limit {}", what, value, limit)
+        }
     }
 }
 
@@ -64,6 +97,37 @@ quick_error! {
         Init(e: ActionError) {
             cause(e)
                 description("action init error")
+                display("action init error: {}", e)
+        }
+
+        Template(e: TemplateError) {
+            cause(e)
+                description("bad template in config")
+        }
+
+        Storage(name: String, e: ActionError) {
+            cause(e)
+                description("storage init error")
+                display("storage {} init error: {}", name, e)
+        }
+
+        UnknownQueue(name: String) {
+            description("unknown queue")
+                display("queue {} is not configured", name)
+        }
+
+        UnsignedOrigin {
+            description("origin is configured without signing keys")
+        }
+
+        ZeroShare(name: String) {
+            description("queue share is zero")
+                display("queue {} has a share of 0", name)
+        }
+
+        TooFewThreads(threads: usize, queues: usize) {
+            description("fewer threads than queues")
+                display("{} threads cannot run {} queues", threads, queues)
         }
     }
 }
@@ -82,6 +146,82 @@ quick_error! {
     }
 }
 
+quick_error! {
+    #[derive(Debug)]
+    pub enum OutboundError {
+        UrlParse(e: UriParseError) {
+            cause(e)
+            description(e.description())
+        }
+        Hyper(e: HyperError) {
+            cause(e)
+            description(e.description())
+        }
+        Status(status: StatusCode) {
+            description("unsuccessful response status")
+            display("unsuccessful response status {}", status)
+        }
+        TooLarge(limit: u64) {
+            description("response body too large")
+            display("response body exceeds limit {}", limit)
+        }
+        Forbidden(reason: String) {
+            description("forbidden by outbound policy")
+            display("forbidden by outbound policy: {}", reason)
+        }
+        TooManyRedirects(limit: u32) {
+            description("too many redirects")
+            display("more than {} redirects", limit)
+        }
+        BadRedirect(location: String) {
+            description("bad redirect location")
+            display("bad redirect location {}", location)
+        }
+        Timeout(timeout: Duration) {
+            description("request timed out")
+            display("request timed out after {:?}", timeout)
+        }
+        Canceled {
+            description("request canceled")
+        }
+        BadResponse(reason: String) {
+            description("unexpected response")
+            display("unexpected response: {}", reason)
+        }
+        Io(e: IoError) {
+            cause(e)
+            description(e.description())
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FormError {
+        Malformed {
+            description("malformed multipart form")
+        }
+        MissingFile {
+            description("form has no file field")
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SignatureError {
+        Missing {
+            description("url is not signed")
+        }
+        Invalid {
+            description("bad url signature")
+        }
+        Expired {
+            description("signed url expired")
+        }
+    }
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum HttpError {
@@ -89,6 +229,10 @@ quick_error! {
             description("BAD URL")
         }
 
+        MethodNotAllowed {
+            description("method not allowed")
+        }
+
         Io (e: IoError) {
             cause(e)
             description(e.description())
@@ -111,5 +255,136 @@ quick_error! {
         SystemTime(e: ::std::time::SystemTimeError) {
             cause(e)
         }
+        Overloaded {
+            description("server is overloaded")
+        }
+        BodyTooLarge(size: u64, limit: u64) {
+            description("request body too large")
+            display("request body of {} bytes exceeds limit {}", size, limit)
+        }
+        Template(e: TemplateError) {
+            cause(e)
+            description("template error")
+        }
+        Signature(e: SignatureError) {
+            cause(e)
+            description(e.description())
+            display("{}", e)
+        }
+        BadRequest(e: JsonError) {
+            cause(e)
+            description("bad request body")
+            display("bad request body: {}", e)
+        }
+        Form(e: FormError) {
+            cause(e)
+            description(e.description())
+            display("{}", e)
+        }
+        UnknownTask(task: String) {
+            description("task is unknown")
+            display("task {} is unknown", task)
+        }
+        SourceNotFound {
+            description("source image not found")
+        }
+        BadCallback(e: OutboundError) {
+            cause(e)
+            description("bad callback url")
+            display("bad callback url: {}", e)
+        }
+        UnknownQueue(name: String) {
+            description("queue is unknown")
+            display("queue {} is unknown", name)
+        }
+        UnsupportedScheme(scheme: String) {
+            description("url scheme is not supported")
+            display("url scheme {:?} is not supported, only http is", scheme)
+        }
+        UnknownJob(id: String) {
+            description("job is unknown")
+            display("job {} is unknown or expired", id)
+        }
+        Outbound(e: OutboundError) {
+            cause(e)
+            description(e.description())
+            display("{}", e)
+        }
+        NoResult {
+            description("preset has no task producing an image")
+        }
+        TaskFailed(task: String, error: String) {
+            description("task failed")
+            display("task {} failed: {}", task, error)
+        }
+        Job(e: JobError) {
+            cause(e)
+            description(e.description())
+            display("{}", e)
+        }
+    }
+}
+
+impl HttpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            &HttpError::UnknownURI |
+            &HttpError::UnknownPreset |
+            &HttpError::SourceNotFound |
+            &HttpError::UnknownJob(_) |
+            &HttpError::Outbound(OutboundError::Status(StatusCode::NotFound)) => StatusCode::NotFound,
+            &HttpError::BodyTooLarge(..) |
+            &HttpError::Job(JobError::TooLarge(..)) => StatusCode::PayloadTooLarge,
+            &HttpError::Job(JobError::Image(ImageError::UnsupportedError(_))) => {
+                StatusCode::UnsupportedMediaType
+            }
+            &HttpError::NoResult |
+            &HttpError::Job(JobError::Image(_)) => StatusCode::UnprocessableEntity,
+            &HttpError::MethodNotAllowed => StatusCode::MethodNotAllowed,
+            &HttpError::Signature(_) => StatusCode::Forbidden,
+            &HttpError::Overloaded => StatusCode::ServiceUnavailable,
+            &HttpError::Outbound(OutboundError::Timeout(_)) => StatusCode::GatewayTimeout,
+            &HttpError::Outbound(OutboundError::Forbidden(_)) => StatusCode::Forbidden,
+            &HttpError::Outbound(_) => StatusCode::BadGateway,
+            &HttpError::Hyper(_) |
+            &HttpError::BadRequest(_) |
+            &HttpError::Form(_) |
+            &HttpError::BadCallback(_) |
+            &HttpError::UnknownQueue(_) |
+            &HttpError::UnsupportedScheme(_) |
+            &HttpError::UnknownTask(_) => StatusCode::BadRequest,
+            _ => StatusCode::InternalServerError,
+        }
+    }
+
+    /// Stable machine readable error name for API clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            &HttpError::UnknownURI => "unknown_uri",
+            &HttpError::UnknownPreset => "unknown_preset",
+            &HttpError::MethodNotAllowed => "method_not_allowed",
+            &HttpError::UnknownJob(_) => "unknown_job",
+            &HttpError::SourceNotFound |
+            &HttpError::Outbound(OutboundError::Status(StatusCode::NotFound)) => "source_not_found",
+            &HttpError::Outbound(OutboundError::Forbidden(_)) => "outbound_forbidden",
+            &HttpError::Outbound(_) => "source_unavailable",
+            &HttpError::BodyTooLarge(..) => "body_too_large",
+            &HttpError::Job(JobError::TooLarge(..)) => "image_too_large",
+            &HttpError::Job(JobError::Image(ImageError::UnsupportedError(_))) => "unsupported_media",
+            &HttpError::Job(JobError::Image(_)) => "decode_failed",
+            &HttpError::Signature(SignatureError::Expired) => "signature_expired",
+            &HttpError::Signature(_) => "bad_signature",
+            &HttpError::Overloaded => "overloaded",
+            &HttpError::TaskFailed(..) => "task_failed",
+            &HttpError::NoResult => "no_result",
+            &HttpError::Hyper(_) |
+            &HttpError::BadRequest(_) => "bad_request",
+            &HttpError::Form(_) => "bad_form",
+            &HttpError::UnknownTask(_) => "unknown_task",
+            &HttpError::BadCallback(_) => "bad_callback",
+            &HttpError::UnknownQueue(_) => "unknown_queue",
+            &HttpError::UnsupportedScheme(_) => "unsupported_scheme",
+            _ => "internal",
+        }
     }
 }
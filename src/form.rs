@@ -0,0 +1,222 @@
+use errors::FormError;
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the form field carrying the image
+pub const FILE_FIELD: &'static str = "file";
+
+/// Contents of a `multipart/form-data` upload
+#[derive(Debug, Default)]
+pub struct Form {
+    pub file: Option<Vec<u8>>,
+    pub fields: HashMap<String, String>,
+}
+
+/// Takes the boundary out of a `multipart/form-data` content type
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(|param| param.trim());
+    match params.next() {
+        Some(mime) if mime.eq_ignore_ascii_case("multipart/form-data") => (),
+        _ => return None,
+    }
+    params
+        .filter_map(|param| {
+                        let mut parts = param.splitn(2, '=');
+                        match (parts.next(), parts.next()) {
+                            (Some(name), Some(value)) if name.eq_ignore_ascii_case("boundary") => {
+                                Some(value.trim_matches('"').to_string())
+                            }
+                            _ => None,
+                        }
+                    })
+        .next()
+}
+
+pub fn parse(body: &[u8], boundary: &str) -> Result<Form, FormError> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let separator = format!("\r\n--{}", boundary).into_bytes();
+    let mut form = Form::default();
+
+    let mut pos = find(body, &delimiter, 0).ok_or(FormError::Malformed)? + delimiter.len();
+    loop {
+        if body[pos..].starts_with(b"--") {
+            return Ok(form);
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return Err(FormError::Malformed);
+        }
+        pos += 2;
+
+        let headers_end = find(body, b"\r\n\r\n", pos).ok_or(FormError::Malformed)?;
+        let headers = String::from_utf8_lossy(&body[pos..headers_end]);
+        let content_start = headers_end + 4;
+        let content_end = find(body, &separator, content_start).ok_or(FormError::Malformed)?;
+        let content = &body[content_start..content_end];
+        pos = content_end + separator.len();
+
+        // files other than the image are of no use
+        let (name, is_file) = part_name(&headers).ok_or(FormError::Malformed)?;
+        if name == FILE_FIELD {
+            form.file = Some(content.to_vec());
+        } else if !is_file {
+            form.fields.insert(name, String::from_utf8_lossy(content).into_owned());
+        }
+    }
+}
+
+/// Field name from part headers, and whether the part is a file
+fn part_name(headers: &str) -> Option<(String, bool)> {
+    let disposition = headers
+        .split("\r\n")
+        .filter_map(|header| {
+            let mut parts = header.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim();
+            if name.eq_ignore_ascii_case("content-disposition") {
+                parts.next()
+            } else {
+                None
+            }
+        })
+        .next()?;
+
+    let mut name = None;
+    let mut is_file = false;
+    for param in disposition.split(';').map(|param| param.trim()) {
+        let mut parts = param.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("name"), Some(value)) => name = Some(value.trim_matches('"').to_string()),
+            (Some("filename"), Some(_)) => is_file = true,
+            _ => (),
+        }
+    }
+    name.map(|name| (name, is_file))
+}
+
+/// Makes boundaries differ even when the clock does not
+static BOUNDARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Random boundary which does not occur in `data`, so the file cannot end its own part
+pub fn new_boundary(data: &[u8]) -> String {
+    loop {
+        // hashers of a new `RandomState` are randomly keyed
+        let mut hasher = RandomState::new().build_hasher();
+        BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        let first = hasher.finish();
+        first.hash(&mut hasher);
+        let boundary = format!("gravure-{:016x}{:016x}", first, hasher.finish());
+        if find(data, boundary.as_bytes(), 0).is_none() {
+            return boundary;
+        }
+    }
+}
+
+/// Makes a `multipart/form-data` body with a single file
+pub fn encode_file(boundary: &str,
+                   field: &str,
+                   filename: &str,
+                   content_type: &str,
+                   data: &[u8])
+                   -> Vec<u8> {
+    let mut body = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"; \
+                            filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                           boundary,
+                           field,
+                           filename,
+                           content_type)
+            .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+/// Knuth-Morris-Pratt search, linear in the haystack however much of the needle repeats in it
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+    if needle.is_empty() {
+        return Some(from);
+    }
+    // length of the longest proper prefix of the needle ending at each of its bytes
+    let mut prefix = vec![0; needle.len()];
+    let mut matched = 0;
+    for i in 1..needle.len() {
+        while matched > 0 && needle[i] != needle[matched] {
+            matched = prefix[matched - 1];
+        }
+        if needle[i] == needle[matched] {
+            matched += 1;
+        }
+        prefix[i] = matched;
+    }
+
+    let mut matched = 0;
+    for (i, &byte) in haystack[from..].iter().enumerate() {
+        while matched > 0 && byte != needle[matched] {
+            matched = prefix[matched - 1];
+        }
+        if byte == needle[matched] {
+            matched += 1;
+        }
+        if matched == needle.len() {
+            return Some(from + i + 1 - needle.len());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn test_parse_form() {
+        let boundary = boundary("multipart/form-data; boundary=\"XyZ\"").unwrap();
+        assert_eq!(boundary, "XyZ");
+        assert_eq!(super::boundary("image/png"), None);
+
+        let body = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"tasks\"\r\n\r\n\
+task1,task2\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+Content-Type: image/png\r\n\r\n\
+\x89PNG\r\n--X\r\n\
+--XyZ--\r\n";
+        let form = parse(body, &boundary).unwrap();
+        assert_eq!(form.file, Some(b"\x89PNG\r\n--X".to_vec()));
+        assert_eq!(form.fields.get("tasks").map(|tasks| tasks.as_str()), Some("task1,task2"));
+
+        assert!(parse(b"--XyZ\r\nbroken", &boundary).is_err());
+
+        let body = encode_file("XyZ", "image", "1.png", "image/png", b"\x89PNG");
+        let form = parse(&body, "XyZ").unwrap();
+        assert_eq!(form.file, None);
+        assert!(form.fields.is_empty());
+        let body = encode_file("XyZ", FILE_FIELD, "1.png", "image/png", b"\x89PNG");
+        assert_eq!(parse(&body, "XyZ").unwrap().file, Some(b"\x89PNG".to_vec()));
+
+        let boundary = new_boundary(b"\x89PNG");
+        assert_ne!(boundary, new_boundary(b"\x89PNG"));
+        let body = encode_file(&boundary, FILE_FIELD, "1.png", "image/png", b"\x89PNG");
+        assert_eq!(parse(&body, &boundary).unwrap().file, Some(b"\x89PNG".to_vec()));
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find(b"aaabaaab", b"aab", 0), Some(1));
+        assert_eq!(find(b"aaabaaab", b"aab", 2), Some(5));
+        assert_eq!(find(b"abababc", b"ababc", 0), Some(2));
+        assert_eq!(find(b"aaaa", b"aab", 0), None);
+        assert_eq!(find(b"ab", b"abc", 0), None);
+        assert_eq!(find(b"ab", b"b", 3), None);
+    }
+}
@@ -0,0 +1,219 @@
+use config::JobsConfig;
+use qs::{TaskReport, TaskStatus};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Status of recent jobs, forgotten after their TTL or when there are too many
+pub struct JobStore {
+    max_jobs: usize,
+    ttl: Duration,
+    records: Mutex<Records>,
+    counter: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Records {
+    jobs: HashMap<String, JobRecord>,
+    // ids in creation order, so the first one expires first
+    order: VecDeque<String>,
+}
+
+/// Tasks run for a single request
+#[derive(Serialize, Clone, Debug)]
+pub struct JobRecord {
+    pub id: String,
+    pub image_id: u64,
+    pub preset: String,
+    pub status: TaskStatus,
+    pub tasks: Vec<TaskState>,
+    #[serde(skip_serializing)]
+    created: Instant,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaskState {
+    pub task: String,
+    pub status: TaskStatus,
+    pub url: Option<String>,
+    pub error: Option<String>,
+}
+
+impl JobRecord {
+    /// Queued until a task starts, then running until every task has finished
+    fn update_status(&mut self) {
+        let (queued, running, failed) = {
+            let count = |status| self.tasks.iter().filter(|task| task.status == status).count();
+            (count(TaskStatus::Queued), count(TaskStatus::Running), count(TaskStatus::Failed))
+        };
+        self.status = if queued > 0 && queued == self.tasks.len() {
+            TaskStatus::Queued
+        } else if queued > 0 || running > 0 {
+            TaskStatus::Running
+        } else if failed > 0 {
+            TaskStatus::Failed
+        } else {
+            TaskStatus::Done
+        };
+    }
+}
+
+impl Records {
+    fn remove_oldest(&mut self) {
+        if let Some(id) = self.order.pop_front() {
+            self.jobs.remove(&id);
+        }
+    }
+}
+
+impl JobStore {
+    pub fn new(config: &JobsConfig) -> JobStore {
+        JobStore {
+            max_jobs: config.max_jobs,
+            ttl: Duration::from_secs(config.ttl),
+            records: Mutex::new(Records::default()),
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records a new job with all its tasks queued, returning its id
+    pub fn create(&self, image_id: u64, preset: &str, tasks: &[(String, Option<String>)]) -> String {
+        let id = self.job_id();
+        let mut record = JobRecord {
+            id: id.clone(),
+            image_id: image_id,
+            preset: preset.to_string(),
+            status: TaskStatus::Queued,
+            tasks: tasks
+                .iter()
+                .map(|&(ref task, ref url)| {
+                         TaskState {
+                             task: task.clone(),
+                             status: TaskStatus::Queued,
+                             url: url.clone(),
+                             error: None,
+                         }
+                     })
+                .collect(),
+            created: Instant::now(),
+        };
+        record.update_status();
+
+        let mut records = self.records.lock().unwrap();
+        self.expire(&mut records);
+        while !records.order.is_empty() && records.order.len() >= self.max_jobs {
+            records.remove_oldest();
+        }
+        records.order.push_back(id.clone());
+        records.jobs.insert(id.clone(), record);
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobRecord> {
+        let mut records = self.records.lock().unwrap();
+        self.expire(&mut records);
+        records.jobs.get(id).cloned()
+    }
+
+    pub fn update(&self, id: &str, task: &str, status: TaskStatus, error: Option<String>) {
+        let mut records = self.records.lock().unwrap();
+        if let Some(record) = records.jobs.get_mut(id) {
+            if let Some(state) = record.tasks.iter_mut().find(|state| state.task == task) {
+                state.status = status;
+                state.error = error;
+            }
+            record.update_status();
+        }
+    }
+
+    fn expire(&self, records: &mut Records) {
+        loop {
+            let expired = match records.order.front() {
+                Some(id) => records.jobs[id].created.elapsed() > self.ttl,
+                None => false,
+            };
+            if !expired {
+                break;
+            }
+            records.remove_oldest();
+        }
+    }
+
+    fn job_id(&self) -> String {
+        let mut hasher = DefaultHasher::default();
+        self.counter.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+/// Reports progress of one task of a job to the store
+#[derive(Clone)]
+pub struct Tracker {
+    pub store: Arc<JobStore>,
+    pub job_id: String,
+}
+
+impl Tracker {
+    pub fn running(&self, task: &str) {
+        self.store
+            .update(&self.job_id, task, TaskStatus::Running, None);
+    }
+
+    pub fn finished(&self, report: &TaskReport) {
+        self.store
+            .update(&self.job_id, &report.task, report.status, report.error.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn store(max_jobs: usize) -> JobStore {
+        JobStore::new(&JobsConfig {
+                           max_jobs: max_jobs,
+                           ttl: 3600,
+                       })
+    }
+
+    #[test]
+    fn test_job_status() {
+        let jobs = store(10);
+        let tasks = vec![("big".to_string(), Some("http://cdn/big/1.jpg".to_string())),
+                         ("small".to_string(), None)];
+        let id = jobs.create(1, "avatar", &tasks);
+        assert_eq!(jobs.get(&id).unwrap().status, TaskStatus::Queued);
+
+        jobs.update(&id, "big", TaskStatus::Running, None);
+        assert_eq!(jobs.get(&id).unwrap().status, TaskStatus::Running);
+        jobs.update(&id, "big", TaskStatus::Done, None);
+        assert_eq!(jobs.get(&id).unwrap().status, TaskStatus::Running);
+        jobs.update(&id, "small", TaskStatus::Failed, Some("bad".to_string()));
+
+        let record = jobs.get(&id).unwrap();
+        assert_eq!(record.status, TaskStatus::Failed);
+        assert_eq!(record.tasks[1].error, Some("bad".to_string()));
+        assert_eq!(record.tasks[0].url, Some("http://cdn/big/1.jpg".to_string()));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let jobs = store(2);
+        let first = jobs.create(1, "avatar", &[]);
+        let second = jobs.create(2, "avatar", &[]);
+        let third = jobs.create(3, "avatar", &[]);
+        assert_ne!(first, second);
+        assert!(jobs.get(&first).is_none());
+        assert_eq!(jobs.get(&second).unwrap().image_id, 2);
+        assert_eq!(jobs.get(&third).unwrap().status, TaskStatus::Done);
+    }
+}
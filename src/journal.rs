@@ -0,0 +1,250 @@
+use config::JournalConfig;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use futures::Future;
+use futures::sync::oneshot;
+use serde_json;
+
+/// Append-only log of accepted jobs, so jobs not finished when the server stops are run again.
+/// Records are written and synced by a thread of its own, which syncs records coming in
+/// meanwhile together. A job is only recorded once its source image is on disk as well.
+pub struct Journal {
+    records: Mutex<Option<mpsc::Sender<Queued>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Record to write, with whoever waits for it to be on disk
+struct Queued {
+    record: Record,
+    synced: Option<oneshot::Sender<io::Result<()>>>,
+}
+
+/// Everything needed to run a preset again on a received source image
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub id: String,
+    pub image_id: u64,
+    pub preset: String,
+    pub image_path: String,
+    #[serde(default)]
+    pub tasks: Option<Vec<String>>,
+    #[serde(default)]
+    pub callback: Option<String>,
+    #[serde(default)]
+    pub queue: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Add(Entry),
+    Done { id: String },
+}
+
+impl Journal {
+    /// Opens the journal, returning jobs still pending in the order they were added.
+    /// The file is rewritten with only those, so it does not grow forever.
+    pub fn open(config: &JournalConfig) -> io::Result<(Journal, Vec<Entry>)> {
+        let path = PathBuf::from(&config.path);
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let mut pending: Vec<Entry> = Vec::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match serde_json::from_str(&line) {
+                        Ok(Record::Add(entry)) => pending.push(entry),
+                        Ok(Record::Done { id }) => pending.retain(|entry| entry.id != id),
+                        // the last record may be cut by a crash while it was written
+                        Err(e) => warn!("skipping broken journal record {}", e),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let temp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&temp)?;
+            for entry in pending.iter() {
+                write_record(&mut file, &Record::Add(entry.clone()))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temp, &path)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        let (tx, rx) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("journal".to_string())
+            .spawn(move || write_records(file, rx))?;
+        let journal = Journal {
+            records: Mutex::new(Some(tx)),
+            writer: Some(writer),
+        };
+        Ok((journal, pending))
+    }
+
+    /// Records an accepted job, which is on disk with its source once the returned future
+    /// resolves
+    pub fn add(&self, entry: &Entry) -> Box<Future<Item = (), Error = io::Error>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Record::Add(entry.clone()), Some(tx));
+        Box::new(rx.then(|synced| match synced {
+                             Ok(synced) => synced,
+                             Err(_) => Err(io::Error::new(io::ErrorKind::Other,
+                                                          "journal writer stopped")),
+                         }))
+    }
+
+    /// Records every task of a job has finished, so it is not replayed. Nothing waits for it,
+    /// a job completed but not recorded is only run again.
+    pub fn complete(&self, id: &str) {
+        self.send(Record::Done { id: id.to_string() }, None);
+    }
+
+    fn send(&self, record: Record, synced: Option<oneshot::Sender<io::Result<()>>>) {
+        let queued = Queued {
+            record: record,
+            synced: synced,
+        };
+        let records = self.records.lock().unwrap();
+        if let Some(ref records) = *records {
+            // the writer only stops with the journal, waiters see it by their dropped sender
+            records.send(queued).ok();
+        }
+    }
+}
+
+impl Drop for Journal {
+    /// Waits for records sent before to be written
+    fn drop(&mut self) {
+        self.records.lock().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+fn write_records(mut file: File, records: mpsc::Receiver<Queued>) {
+    while let Ok(first) = records.recv() {
+        let mut batch = vec![first];
+        batch.extend(records.try_iter());
+        // a record pointing at a source lost by a crash would only be dropped on replay
+        let sources: Vec<io::Result<()>> = batch
+            .iter()
+            .map(|queued| sync_source(&queued.record))
+            .collect();
+        let mut written = Ok(());
+        for (queued, source) in batch.iter().zip(sources.iter()) {
+            if source.is_ok() {
+                written = written.and_then(|_| write_record(&mut file, &queued.record));
+            }
+        }
+        let written = written.and_then(|_| file.sync_data());
+        if let Err(ref e) = written {
+            warn!("could not write journal records {}", e);
+        }
+        for (queued, source) in batch.into_iter().zip(sources) {
+            if let Some(synced) = queued.synced {
+                let result = match (source, &written) {
+                    (Err(e), _) => Err(e),
+                    (Ok(()), &Ok(())) => Ok(()),
+                    (Ok(()), &Err(ref e)) => Err(io::Error::new(e.kind(), e.to_string())),
+                };
+                synced.send(result).ok();
+            }
+        }
+    }
+}
+
+/// Syncs the source image of an added job and its directory entry
+fn sync_source(record: &Record) -> io::Result<()> {
+    if let &Record::Add(ref entry) = record {
+        let path = Path::new(&entry.image_path);
+        File::open(path)?.sync_all()?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(out: &mut W, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    line.push(b'\n');
+    out.write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn entry(source: &Path, id: &str) -> Entry {
+        Entry {
+            id: id.to_string(),
+            image_id: 1,
+            preset: "preset1".to_string(),
+            image_path: source.to_string_lossy().into_owned(),
+            tasks: None,
+            callback: None,
+            queue: None,
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = ::std::env::temp_dir().join(format!("gravure-journal-{}", ::std::process::id()));
+        let config = JournalConfig { path: dir.join("journal").to_string_lossy().into_owned() };
+        let source = dir.join("1.png");
+
+        let (journal, pending) = Journal::open(&config).unwrap();
+        assert!(pending.is_empty());
+        // jobs without their source are not recorded
+        assert!(journal.add(&entry(&source, "lost")).wait().is_err());
+        File::create(&source).unwrap();
+        journal.add(&entry(&source, "a")).wait().unwrap();
+        let added = vec![journal.add(&entry(&source, "b")), journal.add(&entry(&source, "c"))];
+        for added in added {
+            added.wait().unwrap();
+        }
+        journal.complete("b");
+        drop(journal);
+
+        // a record cut by a crash
+        OpenOptions::new()
+            .append(true)
+            .open(&config.path)
+            .and_then(|mut file| file.write_all(b"{\"op\":\"add\",\"id"))
+            .unwrap();
+
+        let (journal, pending) = Journal::open(&config).unwrap();
+        assert_eq!(pending, vec![entry(&source, "a"), entry(&source, "c")]);
+        journal.complete("a");
+        drop(journal);
+
+        let (_, pending) = Journal::open(&config).unwrap();
+        assert_eq!(pending, vec![entry(&source, "c")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
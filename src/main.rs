@@ -14,7 +14,6 @@ extern crate tokio_io;
 extern crate hyper;
 extern crate regex;
 extern crate liquid;
-extern crate multipart;
 extern crate num_cpus;
 #[macro_use]
 extern crate slog;
@@ -23,12 +22,24 @@ extern crate slog_json;
 extern crate slog_async;
 #[macro_use]
 extern crate slog_scope;
+extern crate hmac;
+extern crate sha2;
+extern crate hex;
 
 pub mod config;
 pub mod errors;
+pub mod form;
+pub mod jobs;
+pub mod journal;
 pub mod rest;
 pub mod actions;
+pub mod cache;
+pub mod callback;
+pub mod outbound;
 pub mod qs;
+pub mod s3;
+pub mod signing;
+pub mod storage;
 pub mod template;
 
 use config::*;
@@ -37,7 +48,6 @@ use std::fs::File;
 use serde_json::from_reader;
 use futures::{Future, Stream};
 
-use futures_pool::Pool;
 use tokio_core::net::TcpListener;
 use tokio_core::reactor::Core;
 
@@ -125,23 +135,45 @@ fn main() {
     let log = slog::Logger::root(drain, o!("version" => env!("CARGO_PKG_VERSION")));
     let _guard = slog_scope::set_global_logger(log);
 
-    let pool = || Pool::builder().pool_size(threads).build();
-    // but sets logging scope
-    let (sender, mut pool) = slog_scope::scope(&slog_scope::logger()
-                                                    .new(slog_o!("scope" => "threadpool")),
-                                               pool);
-
     info!("Server starting");
 
     let config = matches.value_of("config").unwrap_or("config_test.json");
     let config = File::open(config).unwrap();
     let mut config: Config = from_reader(config).unwrap();
-    config.init(core.remote()).unwrap();
+    if let Err(e) = config.init(core.remote()) {
+        panic!("bad config: {}", e);
+    }
+    let cache = config
+        .cache
+        .as_ref()
+        .map(|cache| Arc::new(cache::Cache::open(cache).unwrap()));
+    // but sets logging scope
+    let queues = || qs::Queues::start(&config.queues, &config.backlog, threads).unwrap();
+    let (queues, mut pools) = slog_scope::scope(&slog_scope::logger()
+                                                     .new(slog_o!("scope" => "threadpool")),
+                                                queues);
+    let jobs = Arc::new(jobs::JobStore::new(&config.jobs));
+    let (journal, pending) = match config.journal {
+        Some(ref journal) => {
+            let (journal, pending) = journal::Journal::open(journal).unwrap();
+            (Some(Arc::new(journal)), pending)
+        }
+        None => (None, Vec::new()),
+    };
     let config = Arc::new(config);
 
     // Run event loop in main thread
     let listen = listen.parse().unwrap();
 
+    rest::GravureServer::new(config.clone(),
+                             "upload".to_string(),
+                             queues.clone(),
+                             cache.clone(),
+                             jobs.clone(),
+                             journal.clone(),
+                             handle.clone())
+            .replay(pending);
+
     // Bind the server's socket
     let listener = TcpListener::bind(&listen, &handle).unwrap();
     let server = listener
@@ -149,12 +181,17 @@ fn main() {
         .for_each(|(sock, addr)| {
                       let server = rest::GravureServer::new(config.clone(),
                                                             "upload".to_string(),
-                                                            sender.clone(),
+                                                            queues.clone(),
+                                                            cache.clone(),
+                                                            jobs.clone(),
+                                                            journal.clone(),
                                                             handle.clone());
                       Http::new().bind_connection(&handle, sock, addr, server);
                       Ok(())
                   })
         .then(|_| Ok::<(), ()>(()));
     core.run(server).unwrap();
-    pool.shutdown();
+    for pool in pools.iter_mut() {
+        pool.shutdown();
+    }
 }
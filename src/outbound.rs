@@ -0,0 +1,631 @@
+use config::{OutboundConfig, PoolConfig, RetryConfig};
+use errors::OutboundError;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll, Stream};
+use futures::future::{err, ok, loop_fn, Loop};
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Remote, Timeout};
+use hyper::{self, Uri, Method, StatusCode};
+use hyper::header::{ContentLength, Location};
+use hyper::client::{Request, Response, Client, Service};
+use url::Url;
+
+pub type OutboundFuture<T> = Box<Future<Item = T, Error = OutboundError>>;
+
+thread_local! {
+    // clients are bound to the event loop of their thread
+    static CLIENTS: RefCell<Vec<(OutboundConfig, OutboundClient)>> = RefCell::new(Vec::new());
+}
+
+/// Client whose every connection follows outbound `config`. It is made once per event loop and
+/// config, so connections are kept alive and reused by all the requests.
+pub fn client(handle: &Handle, config: &OutboundConfig) -> OutboundClient {
+    // retries are up to callers, so configs differing only by them share connections
+    let mut key = config.clone();
+    key.retry = RetryConfig::default();
+    CLIENTS.with(|clients| {
+        let mut clients = clients.borrow_mut();
+        if let Some(index) = clients.iter().position(|&(ref config, _)| config == &key) {
+            return clients[index].1.clone();
+        }
+        let client = OutboundClient::new(handle, config);
+        clients.push((key, client.clone()));
+        client
+    })
+}
+
+/// Client limiting requests in flight as pool config says. Idle kept alive connections are not
+/// counted, as they are only reused by requests which got a slot.
+#[derive(Clone)]
+pub struct OutboundClient {
+    client: Client<PolicyConnector>,
+    handle: Handle,
+    pool: PoolConfig,
+    slots: Rc<RefCell<Slots>>,
+}
+
+impl OutboundClient {
+    fn new(handle: &Handle, config: &OutboundConfig) -> OutboundClient {
+        let connector = PolicyConnector {
+            config: Arc::new(config.clone()),
+            handle: handle.clone(),
+            resolver: Resolver::new(config.pool.resolver_threads),
+        };
+        let client = Client::configure()
+            .connector(connector)
+            .keep_alive(config.pool.keep_alive)
+            .keep_alive_timeout(config.pool.idle_timeout.map(Duration::from_secs))
+            .build(handle);
+        OutboundClient {
+            client: client,
+            handle: handle.clone(),
+            pool: config.pool.clone(),
+            slots: Rc::new(RefCell::new(Slots::default())),
+        }
+    }
+
+    /// Sends `request` once a slot is free, failing if no response comes within read timeout.
+    /// The slot is returned along, to be held until the response body is read.
+    pub fn request(&self, request: Request) -> OutboundFuture<(Response, Slot)> {
+        let host = format!("{}:{}",
+                           request.uri().host().unwrap_or(""),
+                           request.uri().port().unwrap_or(80));
+        let client = self.client.clone();
+        let handle = self.handle.clone();
+        let read_timeout = self.pool.read_timeout.map(Duration::from_secs);
+        let response = acquire(self.slots.clone(), host, &self.pool)
+            .map_err(OutboundError::Io)
+            .and_then(move |slot| {
+                let response = client.request(request).map_err(request_error);
+                with_timeout(&handle, Box::new(response), read_timeout)
+                    .map(move |response| (response, slot))
+            });
+        Box::new(response)
+    }
+
+    /// Body of a response, failing when no data comes for read timeout
+    pub fn body(&self, response: Response) -> TimedBody {
+        TimedBody {
+            body: response.body(),
+            handle: self.handle.clone(),
+            read_timeout: self.pool.read_timeout.map(Duration::from_secs),
+            timer: None,
+        }
+    }
+}
+
+/// Checks scheme and host of an outbound request against policy
+pub fn check_uri(config: &OutboundConfig, uri: &Uri) -> Result<(), OutboundError> {
+    let scheme = uri.scheme().unwrap_or("");
+    if !config.allowed_schemes.iter().any(|allowed| allowed == scheme) {
+        return Err(OutboundError::Forbidden(format!("scheme {:?} is not allowed", scheme)));
+    }
+    let host = uri.host().unwrap_or("").to_ascii_lowercase();
+    if !config.allowed_hosts.is_empty() &&
+       !config.allowed_hosts.iter().any(|pattern| host_matches(pattern, &host)) {
+        return Err(OutboundError::Forbidden(format!("host {:?} is not allowed", host)));
+    }
+    Ok(())
+}
+
+/// Matches exact host names and `*.domain` patterns for any subdomain
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    if pattern.starts_with("*.") {
+        host.ends_with(&pattern[1..])
+    } else {
+        host == pattern
+    }
+}
+
+/// Tells addresses of internal networks, which are not reachable unless policy permits
+fn is_internal(ip: &IpAddr) -> bool {
+    match ip {
+        &IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast() ||
+            ip.is_unspecified() || ip.is_multicast() || octets[0] == 0 ||
+            // shared address space of carrier-grade NAT
+            (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        }
+        &IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // IPv4 mapped addresses reach IPv4 networks
+            if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+                if let Some(ip) = ip.to_ipv4() {
+                    return is_internal(&IpAddr::V4(ip));
+                }
+            }
+            let first = segments[0];
+            ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() ||
+            // unique local and link local ranges
+            first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// Connector resolving host names itself to refuse internal addresses
+#[derive(Clone)]
+pub struct PolicyConnector {
+    config: Arc<OutboundConfig>,
+    handle: Handle,
+    resolver: Resolver,
+}
+
+impl Service for PolicyConnector {
+    type Request = Uri;
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        if let Err(e) = check_uri(&self.config, &uri) {
+            return Box::new(err(io::Error::new(io::ErrorKind::PermissionDenied, e.to_string())));
+        }
+        let host = uri.host().unwrap_or("").to_string();
+        let port = uri.port().unwrap_or(80);
+
+        let allow_internal = self.config.allow_internal;
+        let connect_timeout = self.config.pool.connect_timeout.map(Duration::from_secs);
+        let handle = self.handle.clone();
+        let connect = self.resolver
+            .resolve(host, port)
+            .and_then(move |addrs| {
+                addrs
+                    .into_iter()
+                    .find(|addr| allow_internal || !is_internal(&addr.ip()))
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::PermissionDenied,
+                                       "host resolves to internal addresses only")
+                    })
+            })
+            .and_then(move |addr| TcpStream::connect(&addr, &handle));
+        io_timeout(&self.handle, Box::new(connect), connect_timeout)
+    }
+}
+
+/// Threads running the system resolver, which blocks, off the event loop. There is a fixed
+/// number of them, lookups beyond it wait for a free one.
+#[derive(Clone)]
+pub struct Resolver {
+    lookups: mpsc::Sender<Lookup>,
+}
+
+struct Lookup {
+    host: String,
+    port: u16,
+    result: oneshot::Sender<io::Result<Vec<SocketAddr>>>,
+}
+
+impl Resolver {
+    /// Starts `threads` resolver threads, which stop once every clone of the resolver is dropped
+    pub fn new(threads: usize) -> Resolver {
+        let (tx, rx) = mpsc::channel::<Lookup>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..cmp::max(threads, 1) {
+            let rx = rx.clone();
+            thread::spawn(move || loop {
+                // the lock is released before resolving, so threads resolve in parallel
+                let lookup = rx.lock().unwrap().recv();
+                let lookup = match lookup {
+                    Ok(lookup) => lookup,
+                    Err(_) => break,
+                };
+                let resolved = (lookup.host.as_str(), lookup.port)
+                    .to_socket_addrs()
+                    .map(|addrs| addrs.collect::<Vec<SocketAddr>>());
+                lookup.result.send(resolved).ok();
+            });
+        }
+        Resolver { lookups: tx }
+    }
+
+    pub fn resolve(&self,
+                   host: String,
+                   port: u16)
+                   -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error>> {
+        let (tx, rx) = oneshot::channel();
+        let lookup = Lookup {
+            host: host,
+            port: port,
+            result: tx,
+        };
+        if self.lookups.send(lookup).is_err() {
+            return Box::new(err(io::Error::new(io::ErrorKind::Other, "resolver stopped")));
+        }
+        Box::new(rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "resolver failed"))
+                     .and_then(|resolved| resolved))
+    }
+}
+
+/// Fails io `future` with `TimedOut` if it is not resolved in `timeout`
+fn io_timeout<T: 'static>(handle: &Handle,
+                          future: Box<Future<Item = T, Error = io::Error>>,
+                          timeout: Option<Duration>)
+                          -> Box<Future<Item = T, Error = io::Error>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return future,
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(err(e)),
+    };
+    let future = future
+        .select(timer.and_then(|_| {
+                                   Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))
+                               }))
+        .map(|(item, _)| item)
+        .map_err(|(e, _)| e);
+    Box::new(future)
+}
+
+/// Counts of requests in flight, in total and by host
+#[derive(Default)]
+struct Slots {
+    total: usize,
+    by_host: HashMap<String, usize>,
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+/// Place of a request in flight, freed on drop
+pub struct Slot {
+    slots: Rc<RefCell<Slots>>,
+    host: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut slots = self.slots.borrow_mut();
+        slots.total -= 1;
+        let left = {
+            let count = slots.by_host.entry(self.host.clone()).or_insert(1);
+            *count -= 1;
+            *count
+        };
+        if left == 0 {
+            slots.by_host.remove(&self.host);
+        }
+        // every waiter checks again, as the freed slot may be of another host
+        for waiter in slots.waiting.drain(..) {
+            waiter.send(()).ok();
+        }
+    }
+}
+
+/// Waits until a request to `host` may be sent within pool limits
+fn acquire(slots: Rc<RefCell<Slots>>,
+           host: String,
+           config: &PoolConfig)
+           -> Box<Future<Item = Slot, Error = io::Error>> {
+    let max_in_flight = config.max_in_flight;
+    let max_per_host = config.max_per_host;
+    let slot = loop_fn((), move |_| {
+        let mut state = slots.borrow_mut();
+        let host_count = state.by_host.get(&host).cloned().unwrap_or(0);
+        let free = max_in_flight.map(|max| state.total < max).unwrap_or(true) &&
+                   max_per_host.map(|max| host_count < max).unwrap_or(true);
+        if free {
+            state.total += 1;
+            *state.by_host.entry(host.clone()).or_insert(0) += 1;
+            let slot = Slot {
+                slots: slots.clone(),
+                host: host.clone(),
+            };
+            return Box::new(ok(Loop::Break(slot))) as Box<Future<Item = _, Error = _>>;
+        }
+        let (tx, rx) = oneshot::channel();
+        state.waiting.push_back(tx);
+        Box::new(rx.then(|_| Ok(Loop::Continue(())))) as Box<Future<Item = _, Error = _>>
+    });
+    Box::new(slot)
+}
+
+/// Response body failing reads which wait for data too long
+pub struct TimedBody {
+    body: hyper::Body,
+    handle: Handle,
+    read_timeout: Option<Duration>,
+    timer: Option<Timeout>,
+}
+
+impl Stream for TimedBody {
+    type Item = hyper::Chunk;
+    type Error = OutboundError;
+
+    fn poll(&mut self) -> Poll<Option<hyper::Chunk>, OutboundError> {
+        match self.body.poll().map_err(OutboundError::Hyper)? {
+            Async::NotReady => (),
+            ready => {
+                self.timer = None;
+                return Ok(ready);
+            }
+        }
+        if let Some(timeout) = self.read_timeout {
+            if self.timer.is_none() {
+                self.timer = Some(Timeout::new(timeout, &self.handle).map_err(OutboundError::Io)?);
+            }
+            // polling the timer also wakes the reading task when it fires
+            let fired = match self.timer {
+                Some(ref mut timer) => timer.poll().map_err(OutboundError::Io)?.is_ready(),
+                None => false,
+            };
+            if fired {
+                self.timer = None;
+                return Err(OutboundError::Timeout(timeout));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Tells policy refusals from other connection errors
+pub fn request_error(e: hyper::Error) -> OutboundError {
+    match e {
+        hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            OutboundError::Forbidden(e.to_string())
+        }
+        e => OutboundError::Hyper(e),
+    }
+}
+
+/// Downloads a whole response body, following redirects and failing on unsuccessful statuses
+/// and bodies over `max_size`
+pub fn download(handle: &Handle,
+                uri: Uri,
+                max_size: Option<u64>,
+                config: &OutboundConfig)
+                -> OutboundFuture<Vec<u8>> {
+    let client = client(handle, config);
+    let body_client = client.clone();
+    let config = config.clone();
+    let max_redirects = config.max_redirects;
+    let response = loop_fn((uri, 0), move |(uri, redirects)| {
+        // every redirect target is checked as well
+        if let Err(e) = check_uri(&config, &uri) {
+            return Box::new(err(e)) as OutboundFuture<_>;
+        }
+        let response = client
+            .request(Request::new(Method::Get, uri.clone()))
+            .and_then(move |(response, slot)| {
+                let location = redirect_location(&uri, &response);
+                match location {
+                    None => Ok(Loop::Break((response, slot))),
+                    Some(_) if redirects >= max_redirects => {
+                        Err(OutboundError::TooManyRedirects(max_redirects))
+                    }
+                    Some(location) => location.map(|uri| Loop::Continue((uri, redirects + 1))),
+                }
+            });
+        Box::new(response) as OutboundFuture<_>
+    });
+
+    let body = response
+        .and_then(move |(response, slot)| {
+            if !response.status().is_success() {
+                return Err(OutboundError::Status(response.status()));
+            }
+            if let (Some(&ContentLength(size)), Some(limit)) =
+                (response.headers().get::<ContentLength>(), max_size) {
+                if size > limit {
+                    return Err(OutboundError::TooLarge(limit));
+                }
+            }
+            Ok((response, slot))
+        })
+        .and_then(move |(response, slot)| {
+            body_client
+                .body(response)
+                .fold(Vec::new(), move |mut body, chunk| {
+                    if let Some(limit) = max_size {
+                        if (body.len() + chunk.len()) as u64 > limit {
+                            return Err(OutboundError::TooLarge(limit));
+                        }
+                    }
+                    body.extend_from_slice(&chunk);
+                    Ok(body)
+                })
+                // the request is in flight until its body is read
+                .then(move |body| {
+                          drop(slot);
+                          body
+                      })
+        });
+    with_timeout(handle, Box::new(body), config.timeout.map(Duration::from_secs))
+}
+
+/// Same as `download`, for urls not parsed yet
+pub fn download_url(handle: &Handle,
+                    url: &str,
+                    max_size: Option<u64>,
+                    config: &OutboundConfig)
+                    -> OutboundFuture<Vec<u8>> {
+    match url.parse() {
+        Ok(uri) => download(handle, uri, max_size, config),
+        Err(e) => Box::new(err(OutboundError::UrlParse(e))),
+    }
+}
+
+/// Fails `future` if it is not resolved in `timeout`
+pub fn with_timeout<T: 'static>(handle: &Handle,
+                                future: OutboundFuture<T>,
+                                timeout: Option<Duration>)
+                                -> OutboundFuture<T> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return future,
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(err(OutboundError::Io(e))),
+    };
+    let future = future
+        .select(timer.then(move |_| Err(OutboundError::Timeout(timeout))))
+        .map(|(item, _)| item)
+        .map_err(|(e, _)| e);
+    Box::new(future)
+}
+
+/// Sends requests made by `request` until one gets a successful response, waiting between
+/// attempts as retry config says. Every attempt has its own timeout. The response comes with
+/// its slot, to be held until its body is read.
+pub fn send_with_retries<F>(handle: &Handle,
+                            config: &OutboundConfig,
+                            request: F)
+                            -> OutboundFuture<(Response, Slot)>
+    where F: Fn() -> Request + 'static
+{
+    let client = client(handle, config);
+    let handle = handle.clone();
+    let retry = config.retry.clone();
+    let timeout = config.timeout.map(Duration::from_secs);
+    let attempts = cmp::max(retry.attempts, 1);
+    let response = loop_fn(0, move |attempt| {
+        let response = client
+            .request(request())
+            .and_then(|(response, slot)| if response.status().is_success() {
+                          Ok((response, slot))
+                      } else {
+                          Err(OutboundError::Status(response.status()))
+                      });
+        let delay = backoff(&retry, attempt);
+        let timer_handle = handle.clone();
+        let response = with_timeout(&handle, Box::new(response), timeout).then(move |result| {
+            let e = match result {
+                Ok(response) => return Box::new(ok(Loop::Break(response))) as OutboundFuture<_>,
+                Err(e) => e,
+            };
+            if attempt + 1 >= attempts || !retryable(&e) {
+                return Box::new(err(e));
+            }
+            warn!("outbound request failed, retrying in {:?}: {}", delay, e);
+            match Timeout::new(delay, &timer_handle) {
+                Ok(timer) => {
+                    Box::new(timer
+                                 .map(move |_| Loop::Continue(attempt + 1))
+                                 .map_err(OutboundError::Io))
+                }
+                Err(e) => Box::new(err(OutboundError::Io(e))),
+            }
+        });
+        Box::new(response) as OutboundFuture<_>
+    });
+    Box::new(response)
+}
+
+/// Runs requests on the event loop of `remote` and waits for them. It blocks the calling thread,
+/// so it is only for job threads, never for the event loop itself.
+pub fn block_on<T, F>(remote: &Remote, requests: F) -> Result<T, OutboundError>
+    where F: FnOnce(&Handle) -> OutboundFuture<T> + Send + 'static,
+          T: Send + 'static
+{
+    let (tx, rx) = oneshot::channel();
+    remote.spawn(move |handle| {
+                     requests(handle).then(move |result| {
+                                               tx.send(result).ok();
+                                               Ok::<(), ()>(())
+                                           })
+                 });
+    rx.wait().unwrap_or(Err(OutboundError::Canceled))
+}
+
+/// Failures which may go away by themselves
+fn retryable(e: &OutboundError) -> bool {
+    match e {
+        &OutboundError::Status(status) => {
+            status.is_server_error() || status == StatusCode::RequestTimeout ||
+            status == StatusCode::TooManyRequests
+        }
+        &OutboundError::UrlParse(_) |
+        &OutboundError::Forbidden(_) |
+        &OutboundError::TooLarge(_) |
+        &OutboundError::TooManyRedirects(_) |
+        &OutboundError::BadRedirect(_) |
+        &OutboundError::BadResponse(_) |
+        &OutboundError::Canceled => false,
+        _ => true,
+    }
+}
+
+/// Delay before the attempt following `attempt`, doubled every time up to the maximum.
+/// Half of it is random, so requests failed together are not retried together.
+pub fn backoff(config: &RetryConfig, attempt: u32) -> Duration {
+    let delay = config
+        .initial_delay
+        .saturating_mul(1 << cmp::min(attempt, 16))
+        .min(config.max_delay);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+    let jitter = hasher.finish() % (delay / 2 + 1);
+    Duration::from_millis(delay - delay / 2 + jitter)
+}
+
+/// Where a redirect response points to, relative locations resolved against the request uri
+fn redirect_location(uri: &Uri, response: &Response) -> Option<Result<Uri, OutboundError>> {
+    match response.status() {
+        StatusCode::MovedPermanently |
+        StatusCode::Found |
+        StatusCode::SeeOther |
+        StatusCode::TemporaryRedirect |
+        StatusCode::PermanentRedirect => (),
+        _ => return None,
+    }
+    let location = match response.headers().get::<Location>() {
+        Some(location) => location.to_string(),
+        None => return None,
+    };
+    let resolved = Url::parse(&uri.to_string())
+        .and_then(|base| base.join(&location))
+        .map_err(|_| OutboundError::BadRedirect(location.clone()))
+        .and_then(|url| url.as_str().parse().map_err(|e| OutboundError::UrlParse(e)));
+    Some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn test_backoff() {
+        let config = RetryConfig {
+            attempts: 5,
+            initial_delay: 100,
+            max_delay: 1000,
+        };
+        for _ in 0..100 {
+            let first = backoff(&config, 0);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = backoff(&config, 2);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            let last = backoff(&config, 30);
+            assert!(last >= Duration::from_millis(500) && last <= Duration::from_millis(1000));
+        }
+        assert!(!retryable(&OutboundError::Status(StatusCode::NotFound)));
+        assert!(retryable(&OutboundError::Status(StatusCode::ServiceUnavailable)));
+    }
+
+    #[test]
+    fn test_resolver() {
+        let resolver = Resolver::new(2);
+        let lookups: Vec<_> = (0..8)
+            .map(|port| resolver.resolve("127.0.0.1".to_string(), 8000 + port))
+            .collect();
+        for (port, lookup) in lookups.into_iter().enumerate() {
+            let addrs = lookup.wait().unwrap();
+            assert_eq!(addrs, vec![SocketAddr::from(([127, 0, 0, 1], 8000 + port as u16))]);
+        }
+    }
+}
@@ -1,54 +1,752 @@
-use config::Task;
+use config::{BacklogConfig, Limits, QueueConfig, Task, DEFAULT_QUEUE};
 use actions::*;
-use errors::JobError;
+use errors::{ActionError, ConfigError, JobError};
+use template::PathTemplate;
+use cache::{self, Cache};
+use jobs::Tracker;
 
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use futures::sync::oneshot;
 use futures::Future;
 use futures::future::lazy;
-use futures_pool::Sender;
+use futures_pool::{Pool, Sender};
+use hex;
+use image::GenericImage;
+use sha2::{Digest, Sha256};
 use slog_scope;
 
+/// Senders of worker pools by queue name
+#[derive(Clone)]
+pub struct Queues {
+    senders: HashMap<String, Sender>,
+    backlog: Arc<Backlog>,
+}
+
+/// Count of tasks queued or running on all queues
+pub struct Backlog {
+    tasks: AtomicUsize,
+    max_tasks: Option<usize>,
+}
+
+/// Place of a task in the backlog, given back when it is dropped
+pub struct Ticket {
+    backlog: Arc<Backlog>,
+}
+
+impl Backlog {
+    pub fn new(config: &BacklogConfig) -> Backlog {
+        Backlog {
+            tasks: AtomicUsize::new(0),
+            max_tasks: config.max_tasks,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        match self.max_tasks {
+            Some(max_tasks) => self.tasks.load(Ordering::SeqCst) >= max_tasks,
+            None => false,
+        }
+    }
+
+    /// Takes places for all `count` tasks of a job at once, or none if they do not fit.
+    /// A job with more tasks than the limit still fits into an empty backlog.
+    pub fn reserve(backlog: &Arc<Backlog>, count: usize) -> Option<Vec<Ticket>> {
+        let mut tasks = backlog.tasks.load(Ordering::SeqCst);
+        loop {
+            if let Some(max_tasks) = backlog.max_tasks {
+                if tasks > 0 && tasks + count > max_tasks {
+                    return None;
+                }
+            }
+            match backlog
+                      .tasks
+                      .compare_exchange(tasks, tasks + count, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(Backlog::tickets(backlog, count)),
+                Err(current) => tasks = current,
+            }
+        }
+    }
+
+    /// Takes places for tasks accepted before, regardless of the limit
+    pub fn admit(backlog: &Arc<Backlog>, count: usize) -> Vec<Ticket> {
+        backlog.tasks.fetch_add(count, Ordering::SeqCst);
+        Backlog::tickets(backlog, count)
+    }
+
+    fn tickets(backlog: &Arc<Backlog>, count: usize) -> Vec<Ticket> {
+        (0..count)
+            .map(|_| Ticket { backlog: backlog.clone() })
+            .collect()
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.backlog.tasks.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Queues {
+    /// Starts a pool for every queue, `threads` shared between them. The default queue gets
+    /// a share of 1 unless configured. Every queue needs a thread of its own.
+    pub fn start(config: &HashMap<String, QueueConfig>,
+                 backlog: &BacklogConfig,
+                 threads: usize)
+                 -> Result<(Queues, Vec<Pool>), ConfigError> {
+        let sizes = pool_sizes(config, threads);
+        if sizes.len() > threads {
+            return Err(ConfigError::TooFewThreads(threads, sizes.len()));
+        }
+        let mut senders = HashMap::new();
+        let mut pools = Vec::new();
+        for (name, size) in sizes {
+            info!("starting queue"; "queue"=>name.clone(), "threads"=>size);
+            let (sender, pool) = Pool::builder().pool_size(size).build();
+            senders.insert(name, sender);
+            pools.push(pool);
+        }
+        let queues = Queues {
+            senders: senders,
+            backlog: Arc::new(Backlog::new(backlog)),
+        };
+        Ok((queues, pools))
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Option<Sender> {
+        self.senders
+            .get(name.unwrap_or(DEFAULT_QUEUE))
+            .cloned()
+    }
+
+    pub fn backlog(&self) -> Arc<Backlog> {
+        self.backlog.clone()
+    }
+
+    /// Queue of jobs no preset or request puts elsewhere, which is always started
+    pub fn default_queue(&self) -> Sender {
+        self.senders[DEFAULT_QUEUE].clone()
+    }
+}
+
+/// Threads of every queue: one each, and the rest of `threads` proportional to shares.
+/// Threads left by rounding down go to the queues which lost the most to it.
+fn pool_sizes(config: &HashMap<String, QueueConfig>, threads: usize) -> Vec<(String, usize)> {
+    let mut shares: Vec<(String, usize)> = config
+        .iter()
+        .map(|(name, queue)| (name.clone(), queue.share as usize))
+        .collect();
+    if !config.contains_key(DEFAULT_QUEUE) {
+        shares.push((DEFAULT_QUEUE.to_string(), 1));
+    }
+    shares.sort();
+    let total = cmp::max(shares.iter().map(|&(_, share)| share).sum::<usize>(), 1);
+    let extra = threads.saturating_sub(shares.len());
+
+    let mut sizes: Vec<(String, usize)> = shares
+        .iter()
+        .map(|&(ref name, share)| (name.clone(), 1 + extra * share / total))
+        .collect();
+    let given = sizes.iter().map(|&(_, size)| size - 1).sum::<usize>();
+    let mut by_remainder: Vec<usize> = (0..shares.len()).collect();
+    // stable, so ties go in name order
+    by_remainder.sort_by_key(|&i| cmp::Reverse(extra * shares[i].1 % total));
+    for &i in by_remainder.iter().take(extra - given) {
+        sizes[i].1 += 1;
+    }
+    sizes
+}
+
 pub struct Job {
     pub image_id: u64,
     pub image_path: String,
+    pub preset: String,
     pub task: Task,
-    pub response: Option<oneshot::Sender<()>>,
+    pub limits: Limits,
+    /// Keep the encoded result in the report instead of only running the actions
+    pub return_image: bool,
+    /// Skip store actions, when results are only sent back to the client
+    pub skip_store: bool,
+    pub response: Option<oneshot::Sender<TaskReport>>,
     pub client: Arc<String>,
+    pub cache: Option<Arc<Cache>>,
+    pub tracker: Option<Tracker>,
+    pub ticket: Ticket,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Outcome of a single task of a preset
+#[derive(Serialize, Debug)]
+pub struct TaskReport {
+    pub task: String,
+    pub status: TaskStatus,
+    pub url: Option<String>,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Bytes of the encoded result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing)]
+    pub image: Option<EncodedImage>,
+    /// Error the task failed with, for responses to tell its kind
+    #[serde(skip_serializing)]
+    pub failure: Option<JobError>,
+}
+
+impl TaskReport {
+    pub fn failed(task: String, error: String) -> Self {
+        TaskReport {
+            task: task,
+            status: TaskStatus::Failed,
+            url: None,
+            error: Some(error),
+            width: None,
+            height: None,
+            size: None,
+            image: None,
+            failure: None,
+        }
+    }
+}
+
+/// Deletes what the tasks of a preset stored for an image, and its cached results
+pub struct Purge {
+    pub image_id: u64,
+    pub preset: String,
+    pub tasks: Vec<Task>,
+    pub cache: Option<Arc<Cache>>,
+    pub response: Option<oneshot::Sender<PurgeReport>>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemovalStatus {
+    Deleted,
+    /// Nothing was stored there
+    Missing,
+    Failed,
+}
+
+/// Outcome of deleting one stored result
+#[derive(Serialize, Clone, Debug)]
+pub struct Removal {
+    pub task: String,
+    pub target: String,
+    pub status: RemovalStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PurgeReport {
+    pub image_id: u64,
+    pub preset: String,
+    pub removed: Vec<Removal>,
+    pub cache_entries: usize,
+}
+
+impl PurgeReport {
+    pub fn failed(&self) -> bool {
+        self.removed
+            .iter()
+            .any(|removal| removal.status == RemovalStatus::Failed)
+    }
+}
+
+impl Purge {
+    pub fn spawn(mut self, executor: Sender) {
+        let response = self.response.take();
+        let run = move || {
+            let report = slog_scope::scope(&slog_scope::logger()
+                                   .new(slog_o!("scope" => "purge", "id"=>self.image_id, "preset"=>self.preset.clone())),
+                              || self.run());
+            if let Some(response) = response {
+                response
+                    .send(report)
+                    .unwrap_or_else(|_| {
+                                        info!("purge response not set");
+                                    });
+            }
+            Ok::<(), ()>(())
+        };
+        oneshot::spawn(lazy(run), &executor).forget();
+    }
+
+    fn run(self) -> PurgeReport {
+        let mut removed = Vec::new();
+        for task in self.tasks.iter() {
+            for action in task.actions.iter() {
+                for (target, result) in action.purge(self.image_id) {
+                    let (status, error) = match result {
+                        Ok(true) => (RemovalStatus::Deleted, None),
+                        Ok(false) => (RemovalStatus::Missing, None),
+                        Err(e) => {
+                            warn!("could not delete {} {}", target, e);
+                            (RemovalStatus::Failed, Some(e.to_string()))
+                        }
+                    };
+                    removed.push(Removal {
+                                     task: task.name.clone(),
+                                     target: target,
+                                     status: status,
+                                     error: error,
+                                 });
+                }
+            }
+        }
+        let cache_entries = match self.cache {
+            Some(ref cache) => cache.purge(self.image_id),
+            None => 0,
+        };
+        PurgeReport {
+            image_id: self.image_id,
+            preset: self.preset,
+            removed: removed,
+            cache_entries: cache_entries,
+        }
+    }
 }
 
 impl Job {
-    pub fn spawn(self, executor: Sender) {
+    pub fn spawn(mut self, executor: Sender) {
+        let response = self.response.take();
+        let tracker = self.tracker.take();
         let run = move || {
-            slog_scope::scope(&slog_scope::logger()
+            if let Some(ref tracker) = tracker {
+                tracker.running(&self.task.name);
+            }
+            let report = slog_scope::scope(&slog_scope::logger()
                                    .new(slog_o!("scope" => "job action", "id"=>self.image_id, "path"=>self.image_path.clone(), "client"=>self.client.clone())),
-                              || self.run().map_err(|e| warn!("job error {}", e)))
+                              || self.run());
+            if let Some(tracker) = tracker {
+                tracker.finished(&report);
+            }
+            if let Some(response) = response {
+                response
+                    .send(report)
+                    .unwrap_or_else(|_| {
+                                        info!("job response not set");
+                                    });
+            }
+            Ok::<(), ()>(())
         };
-        oneshot::spawn(lazy(run).map(|response| if let Some(response) = response {
-                                         response
-                                             .send(())
-                                             .unwrap_or_else(|_| {
-                                                                 info!("job response not set");
-                                                             });
-                                     }),
-                       &executor);
+        // jobs are not waited for through the handle, so it must not cancel them on drop
+        oneshot::spawn(lazy(run), &executor).forget();
     }
 
-    fn run(self) -> Result<Option<oneshot::Sender<()>>, JobError> {
+    fn run(self) -> TaskReport {
+        let url = PathTemplate::new(self.task.url_template.clone())
+            .and_then(|template| template.render_url(self.image_id, &self.task.name))
+            .map_err(|e| warn!("url template error {}", e))
+            .ok();
+        let name = self.task.name.clone();
+        let return_image = self.return_image;
+        let cache = self.cache.clone();
+        let key = match cache {
+            Some(_) if self.stores_result_only() => {
+                self.cache_key()
+                    .map_err(|e| warn!("could not make cache key {}", e))
+                    .ok()
+            }
+            _ => None,
+        };
+        let cached = match (cache.as_ref(), key.as_ref()) {
+            (Some(cache), Some(key)) => cache.get(key),
+            _ => None,
+        };
+
+        let result = match cached {
+            Some(image) => {
+                debug!("using cached result"; "task"=>name.clone());
+                self.store_cached(&image).map(|_| (None, Some(image)))
+            }
+            None => {
+                let encode = cache.is_some() || return_image;
+                self.process().and_then(|(imgd, encoded)| {
+                    // store actions coming last have encoded the result already
+                    let encoded = match encoded {
+                        None if encode => Some(imgd.encode().map_err(|e| JobError::Action(e))?),
+                        encoded => encoded,
+                    };
+                    if let (Some(cache), Some(key), Some(image)) =
+                        (cache.as_ref(), key.as_ref(), encoded.as_ref()) {
+                        cache.put(key, image);
+                    }
+                    Ok((Some(imgd.image.dimensions()), encoded))
+                })
+            }
+        };
+        // sizes are only known of encoded results, nothing is encoded just to measure it
+        let (dimensions, size) = match result {
+            Ok((ref dimensions, ref image)) => {
+                let dimensions = dimensions.or_else(|| {
+                    image
+                        .as_ref()
+                        .and_then(|image| {
+                                      image
+                                          .dimensions()
+                                          .map_err(|e| warn!("could not read result dimensions {}", e))
+                                          .ok()
+                                  })
+                });
+                (dimensions, image.as_ref().map(|image| image.bytes.len()))
+            }
+            Err(_) => (None, None),
+        };
+        let result = result.map(|(_, image)| if return_image { image } else { None });
+        match result {
+            Ok(image) => {
+                TaskReport {
+                    task: name,
+                    status: TaskStatus::Done,
+                    url: url,
+                    error: None,
+                    width: dimensions.map(|(width, _)| width),
+                    height: dimensions.map(|(_, height)| height),
+                    size: size,
+                    image: image,
+                    failure: None,
+                }
+            }
+            Err(e) => {
+                warn!("job error {}", e);
+                let error = e.to_string();
+                TaskReport {
+                    url: url,
+                    failure: Some(e),
+                    ..TaskReport::failed(name, error)
+                }
+            }
+        }
+    }
+
+    /// Whether store actions only write the final result, so a cached one can stand in for it
+    fn stores_result_only(&self) -> bool {
+        self.skip_store ||
+        self.task
+            .actions
+            .iter()
+            .skip_while(|action| !action.stores())
+            .all(|action| action.stores())
+    }
+
+    /// Key of the task result, which changes with source image contents and task actions.
+    /// The digest must stay the same across builds, since cached results outlive the process.
+    fn cache_key(&self) -> io::Result<String> {
+        let mut hasher = Sha256::default();
+        input_field(&mut hasher, self.preset.as_bytes());
+        input_field(&mut hasher, self.task.name.as_bytes());
+        for params in self.task.actions_raw.iter() {
+            hasher.input(&u64_be(params.len() as u64));
+            for param in params.iter() {
+                input_field(&mut hasher, param.as_bytes());
+            }
+        }
+
+        let mut file = File::open(&self.image_path)?;
+        let mut buf = [0; 65536];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.input(&buf[..read]);
+        }
+        Ok(cache::key(self.image_id, &hex::encode(hasher.result())))
+    }
+
+    /// Runs store actions with a cached result, in place of the transforms which made it
+    fn store_cached(&self, image: &EncodedImage) -> Result<(), JobError> {
+        if self.skip_store {
+            return Ok(());
+        }
+        let task_deadline = deadline_in(self.limits.task_timeout);
+        let mut stored = Vec::new();
+        for action in self.task.actions.iter().filter(|action| action.stores()) {
+            let deadline = Deadline::earliest(task_deadline, deadline_in(self.limits.action_timeout));
+            match action.store(self.image_id, image, deadline) {
+                Ok(true) => stored.push(action),
+                // kept what was there, which is not ours to remove
+                Ok(false) => (),
+                Err(ActionError::Timeout(budget)) => {
+                    warn!("task ran out of time"; "task"=>self.task.name.clone());
+                    remove_outputs(&stored, self.image_id, image.extension);
+                    return Err(JobError::Action(ActionError::Timeout(budget)));
+                }
+                Err(e) => return Err(JobError::Action(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the task, returning its result along with its encoding if a store action made it
+    fn process(self) -> Result<(ImageData, Option<EncodedImage>), JobError> {
         let Job {
             image_id,
             image_path,
             task,
-            response,
+            limits,
+            skip_store,
             ..
         } = self;
-        let mut imgd = ImageData::new(image_path, image_id)
+        let task_deadline = deadline_in(limits.task_timeout);
+        let info = ImageInfo::probe(&image_path)
+            .map_err(|e| JobError::Image(e))?;
+        limits.check(&info)?;
+        let mut imgd = ImageData::decode(&image_path, &info, image_id)
             .map_err(|e| JobError::Image(e))?;
 
+        let mut encoded = None;
+        let mut stored = Vec::new();
         for action in task.actions.iter() {
-            imgd = try!(action.run(&mut imgd).map_err(|e| JobError::Action(e)));
+            if skip_store && action.stores() {
+                continue;
+            }
+            let deadline = Deadline::earliest(task_deadline, deadline_in(limits.action_timeout));
+            imgd = match action.run(&mut imgd, deadline) {
+                Ok(output) => {
+                    if output.stored {
+                        stored.push(action);
+                    }
+                    encoded = output.encoded;
+                    output.image_data
+                }
+                Err(ActionError::Timeout(budget)) => {
+                    warn!("task ran out of time"; "task"=>task.name.clone());
+                    if let Ok(extension) = imgd.extension() {
+                        remove_outputs(&stored, image_id, extension);
+                    }
+                    return Err(JobError::Action(ActionError::Timeout(budget)));
+                }
+                Err(e) => return Err(JobError::Action(e)),
+            };
+        }
+        Ok((imgd, encoded))
+    }
+}
+
+fn deadline_in(timeout: Option<u64>) -> Option<Deadline> {
+    timeout.map(|ms| Deadline::after(Duration::from_millis(ms)))
+}
+
+/// Hashes a value with its length, so different fields cannot run into the same input
+fn input_field(hasher: &mut Sha256, value: &[u8]) {
+    hasher.input(&u64_be(value.len() as u64));
+    hasher.input(value);
+}
+
+fn u64_be(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (56 - 8 * i)) as u8;
+    }
+    bytes
+}
+
+/// Deletes results written by actions of a task which did not finish
+fn remove_outputs(actions: &[&Action], id: u64, extension: &str) {
+    for action in actions {
+        if let Err(e) = action.remove_output(id, extension) {
+            warn!("could not remove partial output {}", e);
         }
-        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use config::{OnExists, OutboundConfig};
+    use storage::{LocalStorage, MemoryStorage, Storage};
+    use image::{DynamicImage, ImageFormat};
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    /// Storage taking its time, so the actions after it run out of it
+    struct Slow(Arc<Storage>);
+
+    impl Storage for Slow {
+        fn store(&self, id: u64, path: &str, image: &EncodedImage) -> Result<bool, ActionError> {
+            thread::sleep(Duration::from_millis(20));
+            self.0.store(id, path, image)
+        }
+
+        fn remove(&self, id: u64, extension: &str, path: &str) -> Result<bool, ActionError> {
+            self.0.remove(id, extension, path)
+        }
+    }
+
+    #[test]
+    fn test_backlog() {
+        let backlog = Arc::new(Backlog::new(&BacklogConfig {
+                                                 max_tasks: Some(2),
+                                                 retry_after: 1,
+                                             }));
+        let first = Backlog::reserve(&backlog, 1).unwrap();
+        assert!(!backlog.is_full());
+        // all tasks of a job or none
+        assert!(Backlog::reserve(&backlog, 2).is_none());
+        assert_eq!(backlog.tasks.load(Ordering::SeqCst), 1);
+        let second = Backlog::reserve(&backlog, 1).unwrap();
+        assert!(backlog.is_full());
+        let replayed = Backlog::admit(&backlog, 2);
+        assert_eq!(backlog.tasks.load(Ordering::SeqCst), 4);
+        drop(replayed);
+        drop(first);
+        assert!(!backlog.is_full());
+        drop(second);
+        // a job larger than the limit runs alone
+        let large = Backlog::reserve(&backlog, 3).unwrap();
+        assert_eq!(large.len(), 3);
+        assert!(Backlog::reserve(&backlog, 1).is_none());
+        drop(large);
+        assert_eq!(backlog.tasks.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_input_field() {
+        let digest = |fields: &[&str]| {
+            let mut hasher = Sha256::default();
+            for field in fields {
+                input_field(&mut hasher, field.as_bytes());
+            }
+            hex::encode(hasher.result())
+        };
+        assert_eq!(digest(&["ab", "c"]), digest(&["ab", "c"]));
+        assert_ne!(digest(&["ab", "c"]), digest(&["a", "bc"]));
+        assert_eq!(u64_be(0x0102), [0, 0, 0, 0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_pool_sizes() {
+        assert_eq!(pool_sizes(&HashMap::new(), 8),
+                   vec![(DEFAULT_QUEUE.to_string(), 8)]);
+
+        let mut config = HashMap::new();
+        config.insert("batch".to_string(), QueueConfig { share: 1 });
+        config.insert("interactive".to_string(), QueueConfig { share: 2 });
+        assert_eq!(pool_sizes(&config, 8),
+                   vec![("batch".to_string(), 2),
+                        (DEFAULT_QUEUE.to_string(), 2),
+                        ("interactive".to_string(), 4)]);
+
+        // every thread is given out, none more
+        assert_eq!(pool_sizes(&config, 7),
+                   vec![("batch".to_string(), 2),
+                        (DEFAULT_QUEUE.to_string(), 2),
+                        ("interactive".to_string(), 3)]);
+        assert_eq!(pool_sizes(&config, 3),
+                   vec![("batch".to_string(), 1),
+                        (DEFAULT_QUEUE.to_string(), 1),
+                        ("interactive".to_string(), 1)]);
+
+        config.insert(DEFAULT_QUEUE.to_string(), QueueConfig { share: 3 });
+        for threads in 3..40 {
+            let sizes = pool_sizes(&config, threads);
+            assert_eq!(sizes.iter().map(|&(_, size)| size).sum::<usize>(), threads);
+        }
+    }
+
+    #[test]
+    fn test_timeout_keeps_skipped() {
+        let root = env::temp_dir().join(format!("gravure-test-timeout-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        File::create(root.join("7.png"))
+            .and_then(|mut file| file.write_all(b"old"))
+            .unwrap();
+
+        let core = Core::new().unwrap();
+        let mut context = ActionContext {
+            executor: core.remote(),
+            outbound: Arc::new(OutboundConfig::default()),
+            s3: None,
+            storages: HashMap::new(),
+            save_root: None,
+        };
+        let local = LocalStorage::new(root.to_str().unwrap()).on_exists(OnExists::Skip);
+        context
+            .storages
+            .insert("local".to_string(), Arc::new(Slow(Arc::new(local))));
+        context
+            .storages
+            .insert("memory".to_string(), Arc::new(MemoryStorage::default()));
+        let actions: Vec<Action> = [["store", "local", "{{image_id}}.{{ext}}"],
+                                    ["store", "memory", "{{image_id}}.{{ext}}"]]
+                .iter()
+                .map(|params| {
+                         let params = params.iter().map(|param| param.to_string()).collect();
+                         Action::from_params(&params, &context).unwrap()
+                     })
+                .collect();
+
+        let backlog = Arc::new(Backlog::new(&BacklogConfig::default()));
+        let job = |id| {
+            Job {
+                image_id: id,
+                image_path: String::new(),
+                preset: "preset".to_string(),
+                task: Task {
+                    name: "task".to_string(),
+                    actions: actions.clone(),
+                    actions_raw: Vec::new(),
+                    url_template: String::new(),
+                },
+                limits: Limits {
+                    task_timeout: Some(10),
+                    ..Limits::default()
+                },
+                return_image: false,
+                skip_store: false,
+                response: None,
+                client: Arc::new("test".to_string()),
+                cache: None,
+                tracker: None,
+                ticket: Backlog::admit(&backlog, 1).pop().unwrap(),
+            }
+        };
+        let image = ImageData {
+                image: DynamicImage::new_rgb8(4, 4),
+                image_format: ImageFormat::PNG,
+                id: 7,
+            }
+            .encode()
+            .unwrap();
+
+        // the existing result was skipped, so it stays when the task runs out of time
+        match job(7).store_cached(&image) {
+            Err(JobError::Action(ActionError::Timeout(_))) => (),
+            _ => panic!("deadline not enforced"),
+        }
+        let mut kept = Vec::new();
+        File::open(root.join("7.png"))
+            .and_then(|mut file| file.read_to_end(&mut kept))
+            .unwrap();
+        assert_eq!(kept, b"old");
+
+        // a result the task wrote is removed
+        assert!(job(8).store_cached(&image).is_err());
+        assert!(!root.join("8.png").exists());
+
+        fs::remove_dir_all(&root).unwrap();
     }
 }
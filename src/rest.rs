@@ -1,80 +1,248 @@
-use config::Config;
-use std::fs::File;
-use std::io::Write;
+use config::{Config, Limits, Origin, OutboundConfig, Task};
+use std::fs::{self, File};
+use std::path::Path;
+use std::io::{Read, Write};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use actions::{EncodedImage, ImageInfo};
+use cache::Cache;
+use callback;
+use jobs::{JobStore, Tracker};
+use journal::{self, Journal};
 use errors::*;
+use form::{self, Form};
+use outbound;
 use qs::*;
+use signing;
+use template::PathTemplate;
 
+use image::ImageFormat;
 use regex::Regex;
 use futures::{Future, Stream};
-use futures::future::ok;
+use futures::future::{ok, err, join_all, lazy};
 use futures::sync::oneshot;
 use futures_pool::Sender;
 use tokio_core::reactor::Handle;
 
-use hyper::{self, StatusCode};
+use hyper::{self, Method, StatusCode};
+use hyper::header::{ContentLength, ContentType};
 use hyper::server::{Request, Response, Service};
+use serde::Serialize;
+use serde_json;
+use url::form_urlencoded;
 use slog_scope;
 
 pub struct GravureServer {
     pub config: Arc<Config>,
-    pub ch: Sender,
+    pub queues: Queues,
+    pub cache: Option<Arc<Cache>>,
+    pub jobs: Arc<JobStore>,
+    pub journal: Option<Arc<Journal>>,
     upload_dir: String,
     routes: Vec<(Regex, Route)>,
     handle: Handle,
 }
 
+type HttpFuture = Box<Future<Item = Response, Error = HttpError>>;
+
 enum Route {
     ByPreset,
+    Process,
+    OnTheFly,
+    Fetch,
+    Purge,
+    JobStatus,
+    CacheStats,
     UploadTest,
 }
 
+/// What the response to a preset upload carries
+#[derive(Clone, Copy, PartialEq)]
+enum Reply {
+    /// Nothing, tasks are run in background
+    Accepted,
+    /// Task reports, after all tasks have finished
+    Report,
+    /// Resulting images of all tasks
+    Image,
+}
+
 impl GravureServer {
-    pub fn new(config: Arc<Config>, upload_dir: String, channel: Sender, handle: Handle) -> Self {
+    pub fn new(config: Arc<Config>,
+               upload_dir: String,
+               queues: Queues,
+               cache: Option<Arc<Cache>>,
+               jobs: Arc<JobStore>,
+               journal: Option<Arc<Journal>>,
+               handle: Handle)
+               -> Self {
         let mut routes = Vec::new();
         routes.push((Regex::new("^/v1/upload/([a-z0-9_]+)/([0-9]+)$").unwrap(), Route::ByPreset));
+        routes.push((Regex::new("^/v1/process/([a-z0-9_]+)/([0-9]+)$").unwrap(), Route::Process));
+        routes.push((Regex::new("^/v1/img/([a-z0-9_]+)/([0-9]+)$").unwrap(), Route::OnTheFly));
+        routes.push((Regex::new("^/v1/fetch/([a-z0-9_]+)/([0-9]+)$").unwrap(), Route::Fetch));
+        routes.push((Regex::new("^/v1/images/([a-z0-9_]+)/([0-9]+)$").unwrap(), Route::Purge));
+        routes.push((Regex::new("^/v1/jobs/([0-9a-f]+)$").unwrap(), Route::JobStatus));
+        routes.push((Regex::new("^/v1/cache/stats$").unwrap(), Route::CacheStats));
         routes.push((Regex::new("^/upload/test$").unwrap(), Route::UploadTest));
 
         GravureServer {
             config: config,
-            ch: channel,
+            queues: queues,
+            cache: cache,
+            jobs: jobs,
+            journal: journal,
             upload_dir: upload_dir,
             routes: routes,
             handle: handle,
         }
     }
 
-    fn route(&self, req: Request) -> Result<(), HttpError> {
+    /// Runs again jobs accepted before a restart, which had not finished
+    pub fn replay(&self, pending: Vec<journal::Entry>) {
+        let journal = match self.journal {
+            Some(ref journal) => journal.clone(),
+            None => return,
+        };
+        for entry in pending {
+            info!("replaying job"; "id"=>entry.image_id, "preset"=>entry.preset.clone());
+            let run = self.new_run(entry.preset.clone(),
+                                   entry.image_id,
+                                   Reply::Accepted,
+                                   "journal".to_string(),
+                                   entry.queue.clone(),
+                                   entry.callback.clone());
+            let mut run = match run {
+                Ok(run) => run,
+                Err(e) => {
+                    warn!("could not replay job {}", e);
+                    journal.complete(&entry.id);
+                    continue;
+                }
+            };
+            run.tasks = entry.tasks;
+            run.metadata = entry.metadata;
+            run.journaled = Some(entry.id.clone());
+
+            let journal = journal.clone();
+            let id = entry.id;
+            let started = run.start(entry.image_path)
+                .map(|_| ())
+                .or_else(move |e| {
+                             warn!("could not replay job {}", e);
+                             journal.complete(&id);
+                             Ok(())
+                         });
+            self.handle.spawn(started);
+        }
+    }
+
+    fn route(&self, req: Request) -> HttpFuture {
         let uri = req.uri().clone();
         let uri = uri.path();
         for &(ref re, ref route) in &self.routes {
             if let Some(caps) = re.captures(uri) {
                 match route {
-                    &Route::ByPreset => {
-                        let preset = try!(caps.at(1).ok_or(HttpError::UnknownURI)).to_string();
-                        let id = try!(caps.at(2).ok_or(HttpError::UnknownURI));
-                        let id = try!(id.parse().map_err(|_| HttpError::UnknownURI));
-                        return self.by_preset(req, preset, id);
+                    &Route::ByPreset | &Route::Process | &Route::OnTheFly | &Route::Fetch |
+                    &Route::Purge => {
+                        let preset = caps.at(1).map(|preset| preset.to_string());
+                        let id = caps.at(2).and_then(|id| id.parse().ok());
+                        let (preset, id) = match (preset, id) {
+                            (Some(preset), Some(id)) => (preset, id),
+                            _ => return Box::new(err(HttpError::UnknownURI)),
+                        };
+                        let reply = match route {
+                            &Route::Process | &Route::OnTheFly => Reply::Image,
+                            _ if sync_requested(&req) => Reply::Report,
+                            _ => Reply::Accepted,
+                        };
+                        return match route {
+                                   &Route::OnTheFly => self.on_the_fly(req, preset, id),
+                                   &Route::Fetch => self.fetch(req, preset, id, reply),
+                                   &Route::Purge => self.purge(req, preset, id),
+                                   _ => self.by_preset(req, preset, id, reply),
+                               };
                     }
+                    &Route::JobStatus => return self.job_status(caps.at(1).unwrap_or_default()),
+                    &Route::CacheStats => return self.cache_stats(),
                     &Route::UploadTest => return self.upload_test(req),
                 }
             }
         }
-        Err(HttpError::UnknownURI)
+        Box::new(err(HttpError::UnknownURI))
     }
 
-    fn by_preset(&self, req: Request, preset_name: String, id: u64) -> Result<(), HttpError> {
-        if !self.config.presets.contains_key(&preset_name) {
-            return Err(HttpError::UnknownPreset);
+    fn preset_run(&self,
+                  req: &Request,
+                  preset_name: String,
+                  id: u64,
+                  reply: Reply)
+                  -> Result<PresetRun, HttpError> {
+        // refused before anything is received or downloaded
+        if self.queues.backlog().is_full() {
+            return Err(HttpError::Overloaded);
         }
+        let client = match req.remote_addr() {
+            Some(addr) => format!("{}", addr),
+            None => "unknown".to_string(),
+        };
+        let queue = header_value(req, QUEUE_HEADER);
+        let callback = callback_requested(req);
+        self.new_run(preset_name, id, reply, client, queue, callback)
+    }
 
-        let config = self.config.clone();
-        let (_resp, _rx) = oneshot::channel::<Job>();
+    fn new_run(&self,
+               preset_name: String,
+               id: u64,
+               reply: Reply,
+               client: String,
+               queue: Option<String>,
+               callback: Option<String>)
+               -> Result<PresetRun, HttpError> {
+        let (limits, callback) = match self.config.presets.get(&preset_name) {
+            Some(preset) => {
+                (self.config.limits(preset), callback.or_else(|| preset.callback.clone()))
+            }
+            None => return Err(HttpError::UnknownPreset),
+        };
+        let chan = {
+            let name = queue
+                .as_ref()
+                .or(self.config.presets[&preset_name].queue.as_ref());
+            match self.queues.get(name.map(|name| name.as_str())) {
+                Some(chan) => chan,
+                None => return Err(HttpError::UnknownQueue(name.cloned().unwrap_or_default())),
+            }
+        };
 
+        Ok(PresetRun {
+               config: self.config.clone(),
+               chan: chan,
+               queue: queue,
+               backlog: self.queues.backlog(),
+               cache: self.cache.clone(),
+               jobs: self.jobs.clone(),
+               journal: self.journal.clone(),
+               journaled: None,
+               client: Arc::new(client),
+               preset: preset_name,
+               id: id,
+               limits: limits,
+               reply: reply,
+               tasks: None,
+               callback: callback,
+               metadata: HashMap::new(),
+               handle: self.handle.clone(),
+           })
+    }
+
+    /// Unique path in upload directory to receive a source image to
+    fn upload_path(&self, preset_name: &str, id: u64) -> Result<String, HttpError> {
         let mut hasher = DefaultHasher::default();
         preset_name.hash(&mut hasher);
         id.hash(&mut hasher);
@@ -84,54 +252,264 @@ impl GravureServer {
             .hash(&mut hasher);
         let hash_str = hasher.finish().to_string();
 
-        let filename = self.upload_dir.clone() + "/" + &hash_str + ".png";
+        Ok(self.upload_dir.clone() + "/" + &hash_str + ".png")
+    }
 
-        let mut file = File::create(filename.clone())
-            .map_err(|e| HttpError::Io(e))?;
-        let chan = self.ch.clone();
-        let client_log = match req.remote_addr() {
-            Some(addr) => format!("{}", addr),
-            None => "unknown".to_string(),
+    fn by_preset(&self,
+                 req: Request,
+                 preset_name: String,
+                 id: u64,
+                 reply: Reply)
+                 -> HttpFuture {
+        let run = match self.preset_run(&req, preset_name, id, reply) {
+            Ok(run) => run,
+            Err(e) => return Box::new(err(e)),
         };
 
-        let client_log = Arc::new(client_log);
-        let client = client_log.clone();
+        // refuse declared oversized bodies before creating any file
+        let max_body_size = run.limits.max_body_size;
+        if let (Some(&ContentLength(size)), Some(limit)) =
+            (req.headers().get::<ContentLength>(), max_body_size) {
+            if size > limit {
+                return Box::new(err(HttpError::BodyTooLarge(size, limit)));
+            }
+        }
+
+        let boundary = req.headers()
+            .get::<ContentType>()
+            .and_then(|content_type| form::boundary(&content_type.to_string()));
+
+        let filename = match self.upload_path(&run.preset, id) {
+            Ok(filename) => filename,
+            Err(e) => return Box::new(err(e)),
+        };
+        let mut file = match File::create(filename.clone()) {
+            Ok(file) => file,
+            Err(e) => return Box::new(err(HttpError::Io(e))),
+        };
+
+        let client_log = run.client.clone();
+        let partial = filename.clone();
         let read_body = req.body()
+            .map_err(|e| HttpError::Hyper(e))
             .fold(0, move |bytes, chunk| {
-                // we fold to count bytes received
-                file.write(chunk.as_ref())
-                    .map_err(|e| hyper::Error::Io(e))
-                    .map(|add| bytes + add)
+                // we fold to count bytes received, and stop as soon as
+                // a body without (or with a false) Content-Length gets too big
+                let bytes = bytes + chunk.len() as u64;
+                if let Some(limit) = max_body_size {
+                    if bytes > limit {
+                        return Err(HttpError::BodyTooLarge(bytes, limit));
+                    }
+                }
+                file.write_all(chunk.as_ref())
+                    .map_err(|e| HttpError::Io(e))
+                    .map(|_| bytes)
             })
             .and_then(move |bytes| {
-                          info!("Received {:?} bytes", bytes; "handler"=>"upload", "client"=>client_log.clone());
-                          Ok(())
-                      })
-            .and_then(move |_| {
-                let preset = config.presets.get(&preset_name).unwrap();
-                // TODO: think when notification needs and does it
-                for task in &preset.tasks {
-                    let job = Job {
-                        image_id: id,
-                        image_path: filename.to_string(),
-                        task: task.clone(),
-                        //response: Some(resp),
-                        response: None,
-                        client: client.clone(),
-                    };
-
-                    job.spawn(chan.clone());
+                info!("Received {:?} bytes", bytes; "handler"=>"upload", "client"=>client_log.clone());
+                let boundary = match boundary {
+                    Some(boundary) => boundary,
+                    None => return Box::new(ok((run, filename))) as Box<Future<Item = _, Error = _>>,
+                };
+                let form = extract_form(filename.clone(), boundary, &run.chan)
+                    .map(move |form| {
+                             let mut run = run;
+                             run.apply_form(form);
+                             (run, filename)
+                         });
+                Box::new(form) as Box<Future<Item = _, Error = _>>
+            })
+            .and_then(|(run, filename)| {
+                // nothing is kept of requests answered with the image
+                let answered = if run.reply == Reply::Image {
+                    Some(filename.clone())
+                } else {
+                    None
+                };
+                run.start(filename).map(move |response| {
+                    if let Some(filename) = answered {
+                        fs::remove_file(&filename).unwrap_or_else(|e| {
+                            warn!("could not remove answered upload {}", e);
+                        });
+                    }
+                    response
+                })
+            })
+            .or_else(move |e| {
+                // the upload is of no use if it was not accepted
+                fs::remove_file(&partial).unwrap_or_else(|e| {
+                    warn!("could not remove rejected upload {}", e);
+                });
+                Err(e)
+            });
+        Box::new(read_body)
+    }
+
+    /// Runs a preset on a source image taken from the configured origin and returns the result
+    fn on_the_fly(&self, req: Request, preset_name: String, id: u64) -> HttpFuture {
+        // only answered with a result, so nothing else may go through it
+        match req.method() {
+            &Method::Get | &Method::Head => (),
+            _ => return Box::new(err(HttpError::MethodNotAllowed)),
+        }
+        // config requires keys with origins, refused anyway in case it did not
+        let verified = match self.config.signing {
+            Some(ref signing) => signing::verify(signing, req.path(), req.query()),
+            None => Err(SignatureError::Missing),
+        };
+        if let Err(e) = verified {
+            return Box::new(err(HttpError::Signature(e)));
+        }
+
+        let run = match self.preset_run(&req, preset_name, id, Reply::Image) {
+            Ok(run) => run,
+            Err(e) => return Box::new(err(e)),
+        };
+        let origin = {
+            let preset = &self.config.presets[&run.preset];
+            match self.config.origin(preset) {
+                Some(origin) => origin.clone(),
+                None => return Box::new(err(HttpError::UnknownURI)),
+            }
+        };
+        let location = match PathTemplate::new(origin.template().to_string())
+                  .and_then(|template| template.render_id(id)) {
+            Ok(location) => location,
+            Err(e) => return Box::new(err(HttpError::Template(e))),
+        };
+
+        match origin {
+            Origin::Local { .. } => {
+                if !Path::new(&location).is_file() {
+                    return Box::new(err(HttpError::SourceNotFound));
                 }
+                run.start(location)
+            }
+            Origin::Http { .. } => {
+                let fetch = self.fetch_source(&run, &location)
+                    .and_then(move |filename| run.start(filename.clone()).then(move |result| {
+                        // downloaded sources are only needed for this response
+                        fs::remove_file(&filename).unwrap_or_else(|e| {
+                            warn!("could not remove fetched source {}", e);
+                        });
+                        result
+                    }));
+                Box::new(fetch)
+            }
+        }
+    }
 
-                Ok(())
+    /// Runs a preset on a source image downloaded from the url in request body
+    fn fetch(&self, req: Request, preset_name: String, id: u64, reply: Reply) -> HttpFuture {
+        let run = match self.preset_run(&req, preset_name, id, reply) {
+            Ok(run) => run,
+            Err(e) => return Box::new(err(e)),
+        };
+        let handle = self.handle.clone();
+        let outbound = self.config.outbound.clone();
+        let upload_path = match self.upload_path(&run.preset, id) {
+            Ok(upload_path) => upload_path,
+            Err(e) => return Box::new(err(e)),
+        };
+
+        let fetch = req.body()
+            .map_err(|e| HttpError::Hyper(e))
+            .fold(Vec::new(), |mut body, chunk| {
+                if body.len() + chunk.len() > MAX_JSON_SIZE {
+                    return Err(HttpError::BodyTooLarge((body.len() + chunk.len()) as u64,
+                                                       MAX_JSON_SIZE as u64));
+                }
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .and_then(|body| {
+                          serde_json::from_slice::<FetchRequest>(&body)
+                              .map_err(|e| HttpError::BadRequest(e))
+                      })
+            .and_then(|request| check_scheme(&request.url).map(|_| request))
+            .and_then(move |request| {
+                info!("fetching source"; "url"=>request.url.clone());
+                download_to(&handle, &request.url, upload_path, &run.limits, &outbound)
+                    .map(move |filename| (run, filename))
+            })
+            .and_then(|(run, filename)| {
+                let partial = filename.clone();
+                run.start(filename).or_else(move |e| {
+                    // the source is of no use if it was not accepted
+                    fs::remove_file(&partial).unwrap_or_else(|e| {
+                        warn!("could not remove rejected source {}", e);
+                    });
+                    Err(e)
+                })
             });
-        self.handle.spawn(read_body.then(|_| Ok(())));
-        Ok(())
+        Box::new(fetch)
     }
 
-    fn upload_test(&self, req: Request) -> Result<(), HttpError> {
+    /// Downloads a source from an origin, which is configured rather than given by the client
+    fn fetch_source(&self, run: &PresetRun, url: &str) -> Box<Future<Item = String, Error = HttpError>> {
+        match self.upload_path(&run.preset, run.id) {
+            Ok(filename) => {
+                let outbound = self.config.configured_outbound();
+                download_to(&self.handle, url, filename, &run.limits, &outbound)
+            }
+            Err(e) => Box::new(err(e)),
+        }
+    }
+
+    /// Deletes every stored result of an image for a preset, along with its cached results
+    fn purge(&self, req: Request, preset_name: String, id: u64) -> HttpFuture {
+        if req.method() != &Method::Delete {
+            return Box::new(err(HttpError::MethodNotAllowed));
+        }
+        let tasks = match self.config.presets.get(&preset_name) {
+            Some(preset) => preset.tasks.clone(),
+            None => return Box::new(err(HttpError::UnknownPreset)),
+        };
+        info!("purging image"; "preset"=>preset_name.clone(), "id"=>id);
+
+        let (tx, rx) = oneshot::channel();
+        let purge = Purge {
+            image_id: id,
+            preset: preset_name,
+            tasks: tasks,
+            cache: self.cache.clone(),
+            response: Some(tx),
+        };
+        purge.spawn(self.queues.default_queue());
+
+        let response = rx.map_err(|_| HttpError::Send("purge was dropped".to_string()))
+            .map(|report| {
+                     let status = if report.failed() {
+                         StatusCode::InternalServerError
+                     } else {
+                         StatusCode::Ok
+                     };
+                     json_response(status, &report)
+                 });
+        Box::new(response)
+    }
+
+    fn job_status(&self, id: &str) -> HttpFuture {
+        match self.jobs.get(id) {
+            Some(record) => Box::new(ok(json_response(StatusCode::Ok, &record))),
+            None => Box::new(err(HttpError::UnknownJob(id.to_string()))),
+        }
+    }
+
+    fn cache_stats(&self) -> HttpFuture {
+        let stats = match self.cache {
+            Some(ref cache) => cache.stats(),
+            None => return Box::new(err(HttpError::UnknownURI)),
+        };
+        Box::new(ok(json_response(StatusCode::Ok, &stats)))
+    }
+
+    fn upload_test(&self, req: Request) -> HttpFuture {
         let filename = "upload/image.png";
-        let mut file = try!(File::create(filename).map_err(|e| HttpError::Io(e)));
+        let mut file = match File::create(filename) {
+            Ok(file) => file,
+            Err(e) => return Box::new(err(HttpError::Io(e))),
+        };
         let read_body = req.body()
             .fold(0, move |bytes, chunk| {
                 // we fold to count bytes received
@@ -139,15 +517,491 @@ impl GravureServer {
                     .map_err(|e| hyper::Error::Io(e))
                     .map(|add| bytes + add)
             })
+            .map_err(|e| HttpError::Hyper(e))
             .and_then(move |bytes| {
                           info!("Received {:?} bytes", bytes; "handler"=>"upload");
-                          Ok(())
+                          Ok(Response::new())
                       });
-        self.handle.spawn(read_body.then(|_| Ok(())));
-        Ok(())
+        Box::new(read_body)
+    }
+}
+
+/// Header with the url the job report is posted to
+const CALLBACK_HEADER: &'static str = "X-Callback-Url";
+/// Header naming the queue jobs of the request are run on
+const QUEUE_HEADER: &'static str = "X-Gravure-Queue";
+
+/// Largest JSON request body accepted
+const MAX_JSON_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct FetchRequest {
+    url: String,
+}
+
+/// Replaces a received form with the image it carries, returning its other fields. The whole
+/// body is read and scanned, so it is done on the worker pool instead of the event loop.
+fn extract_form(filename: String,
+                boundary: String,
+                executor: &Sender)
+                -> Box<Future<Item = Form, Error = HttpError>> {
+    let (tx, rx) = oneshot::channel();
+    let extract = move || {
+        let extracted = replace_form(&filename, &boundary);
+        tx.send(extracted).ok();
+        Ok::<(), ()>(())
+    };
+    oneshot::spawn(lazy(extract), executor).forget();
+    Box::new(rx.map_err(|_| HttpError::Send("form parsing was dropped".to_string()))
+                 .and_then(|extracted| extracted))
+}
+
+fn replace_form(filename: &str, boundary: &str) -> Result<Form, HttpError> {
+    let mut body = Vec::new();
+    File::open(filename)
+        .and_then(|mut file| file.read_to_end(&mut body))
+        .map_err(|e| HttpError::Io(e))?;
+    let mut form = form::parse(&body, boundary).map_err(|e| HttpError::Form(e))?;
+    let image = form.file
+        .take()
+        .ok_or(HttpError::Form(FormError::MissingFile))?;
+    File::create(filename)
+        .and_then(|mut file| file.write_all(&image))
+        .map_err(|e| HttpError::Io(e))?;
+    Ok(form)
+}
+
+/// Outbound connections have no TLS, so anything but plain http is refused before connecting
+fn check_scheme(url: &str) -> Result<(), HttpError> {
+    match url.parse::<hyper::Uri>() {
+        Ok(ref uri) if uri.scheme() != Some("http") => {
+            Err(HttpError::UnsupportedScheme(uri.scheme().unwrap_or("").to_string()))
+        }
+        // malformed urls are reported by the download
+        _ => Ok(()),
+    }
+}
+
+/// Downloads a source image into `filename`, which is removed if anything fails
+fn download_to(handle: &Handle,
+               url: &str,
+               filename: String,
+               limits: &Limits,
+               outbound: &OutboundConfig)
+               -> Box<Future<Item = String, Error = HttpError>> {
+    let partial = filename.clone();
+    let download = outbound::download_url(handle, url, limits.max_body_size, outbound)
+        .map_err(|e| HttpError::Outbound(e))
+        .and_then(move |body| {
+                      File::create(&filename)
+                          .and_then(|mut file| file.write_all(&body))
+                          .map_err(|e| HttpError::Io(e))
+                          .map(|_| filename)
+                  })
+        .or_else(move |e| {
+                     fs::remove_file(&partial).ok();
+                     Err(e)
+                 });
+    Box::new(download)
+}
+
+/// Everything needed to run a preset once its source image is received
+struct PresetRun {
+    config: Arc<Config>,
+    chan: Sender,
+    /// Queue named by the request
+    queue: Option<String>,
+    backlog: Arc<Backlog>,
+    cache: Option<Arc<Cache>>,
+    jobs: Arc<JobStore>,
+    journal: Option<Arc<Journal>>,
+    /// Journal record of a replayed job
+    journaled: Option<String>,
+    client: Arc<String>,
+    preset: String,
+    id: u64,
+    limits: Limits,
+    reply: Reply,
+    /// Names of tasks to run instead of all preset tasks
+    tasks: Option<Vec<String>>,
+    /// Url the report is posted to once every task has finished
+    callback: Option<String>,
+    /// Client fields passed along with the upload
+    metadata: HashMap<String, String>,
+    handle: Handle,
+}
+
+impl PresetRun {
+    /// Takes extra fields of a received form
+    fn apply_form(&mut self, mut form: Form) {
+        if let Some(tasks) = form.fields.remove("tasks") {
+            let tasks = tasks
+                .split(',')
+                .map(|task| task.trim().to_string())
+                .filter(|task| !task.is_empty())
+                .collect();
+            self.tasks = Some(tasks);
+        }
+        if let Some(callback) = form.fields.remove("callback") {
+            self.callback = Some(callback);
+        }
+        if let Some(sync) = form.fields.remove("sync") {
+            if self.reply == Reply::Accepted && sync != "0" && sync != "false" {
+                self.reply = Reply::Report;
+            }
+        }
+        self.metadata = form.fields;
+    }
+
+    /// Checks the source image and spawns a job for every task of the preset
+    fn start(self, filename: String) -> HttpFuture {
+        let PresetRun {
+            config,
+            chan,
+            queue,
+            backlog,
+            cache,
+            jobs,
+            journal,
+            journaled,
+            client,
+            preset,
+            id,
+            limits,
+            reply,
+            tasks,
+            callback,
+            metadata,
+            handle,
+        } = self;
+
+        // results sent back in the response need no callback, and signed urls do not cover it
+        let callback = if reply == Reply::Image { None } else { callback };
+        if let Some(ref url) = callback {
+            if let Err(e) = callback::check_url(&config.outbound, url) {
+                return Box::new(err(HttpError::BadCallback(e)));
+            }
+        }
+
+        // reject oversized images by their headers, before any job decodes them
+        let checked = ImageInfo::probe(&filename)
+            .map_err(|e| JobError::Image(e))
+            .and_then(|info| limits.check(&info));
+        if let Err(e) = checked {
+            return Box::new(err(HttpError::Job(e)));
+        }
+
+        let selected: Vec<Task> = {
+            let preset_tasks = &config.presets[&preset].tasks;
+            if let Some(ref names) = tasks {
+                if let Some(name) = names
+                       .iter()
+                       .find(|name| !preset_tasks.iter().any(|task| &task.name == *name)) {
+                    return Box::new(err(HttpError::UnknownTask(name.clone())));
+                }
+            }
+            preset_tasks
+                .iter()
+                .filter(|task| tasks.as_ref().map_or(true, |names| names.contains(&task.name)))
+                .cloned()
+                .collect()
+        };
+
+        // the backlog may have filled while the source was received, replayed jobs were
+        // accepted already though
+        let tickets = if journaled.is_some() {
+            Backlog::admit(&backlog, selected.len())
+        } else {
+            match Backlog::reserve(&backlog, selected.len()) {
+                Some(tickets) => tickets,
+                None => return Box::new(err(HttpError::Overloaded)),
+            }
+        };
+
+        // results sent back in the response need no polling
+        let job_id = if reply != Reply::Image {
+            let states: Vec<_> = selected
+                .iter()
+                .map(|task| {
+                    let url = PathTemplate::new(task.url_template.clone())
+                        .and_then(|template| template.render_url(id, &task.name))
+                        .ok();
+                    (task.name.clone(), url)
+                })
+                .collect();
+            Some(jobs.create(id, &preset, &states))
+        } else {
+            None
+        };
+
+        // accepted jobs are answered before they finish, so they must survive a restart.
+        // Jobs are only spawned once they are on disk, so their completion comes after.
+        let journaled: Box<Future<Item = _, Error = _>> = match journal {
+            Some(ref journal) if reply == Reply::Accepted => {
+                match journaled {
+                    Some(journaled) => Box::new(ok(Some((journal.clone(), journaled)))),
+                    None => {
+                        let entry = journal::Entry {
+                            id: job_id.clone().unwrap_or_default(),
+                            image_id: id,
+                            preset: preset.clone(),
+                            image_path: filename.clone(),
+                            tasks: tasks.clone(),
+                            callback: callback.clone(),
+                            queue: queue.clone(),
+                            metadata: metadata.clone(),
+                        };
+                        let journal = journal.clone();
+                        Box::new(journal
+                                     .add(&entry)
+                                     .map_err(|e| HttpError::Io(e))
+                                     .map(move |_| Some((journal, entry.id))))
+                    }
+                }
+            }
+            _ => Box::new(ok(None)),
+        };
+        Box::new(journaled.and_then(move |journaled| -> HttpFuture {
+            let background = callback.is_some() || journaled.is_some();
+
+            let mut reports = Vec::new();
+            for (task, ticket) in selected.into_iter().zip(tickets) {
+                let response = if reply != Reply::Accepted || background {
+                    let (tx, rx) = oneshot::channel();
+                    let name = task.name.clone();
+                    // dropped jobs never ran, for example when the pool is shut down
+                    reports.push(rx.map(|report| (report, false)).or_else(move |_| {
+                        let report = TaskReport::failed(name, "job was dropped".to_string());
+                        Ok::<_, HttpError>((report, true))
+                    }));
+                    Some(tx)
+                } else {
+                    None
+                };
+                let job = Job {
+                    image_id: id,
+                    image_path: filename.clone(),
+                    preset: preset.clone(),
+                    task: task,
+                    limits: limits.clone(),
+                    return_image: reply == Reply::Image,
+                    skip_store: reply == Reply::Image,
+                    response: response,
+                    client: client.clone(),
+                    cache: cache.clone(),
+                    tracker: job_id
+                        .as_ref()
+                        .map(|job_id| {
+                                 Tracker {
+                                     store: jobs.clone(),
+                                     job_id: job_id.clone(),
+                                 }
+                             }),
+                    ticket: ticket,
+                };
+
+                job.spawn(chan.clone());
+            }
+
+            let accepted = JobAccepted { job_id: job_id.clone() };
+            let callback = callback.map(|url| (url, config.clone(), handle.clone()));
+            let finished = join_all(reports).map(move |results| {
+                let dropped = results.iter().any(|&(_, dropped)| dropped);
+                let tasks = results.into_iter().map(|(task, _)| task).collect();
+                let report = PresetReport {
+                    job_id: job_id,
+                    image_id: id,
+                    preset: preset,
+                    tasks: tasks,
+                    metadata: metadata,
+                };
+                if let Some((url, config, handle)) = callback {
+                    send_callback(&handle, &config, &url, &report);
+                }
+                // jobs which did not run are left to be replayed
+                if let (Some((journal, id)), false) = (journaled, dropped) {
+                    journal.complete(&id);
+                }
+                report
+            });
+
+            match reply {
+                Reply::Accepted => {
+                    if background {
+                        handle.spawn(finished.map(|_| ()).map_err(|_| ()));
+                    }
+                    Box::new(ok(json_response(StatusCode::Ok, &accepted)))
+                }
+                Reply::Report => {
+                    Box::new(finished.map(|report| json_response(StatusCode::Ok, &report)))
+                }
+                Reply::Image => {
+                    Box::new(finished.and_then(|report| image_response(report.tasks)))
+                }
+            }
+        }))
     }
 }
 
+#[derive(Serialize)]
+struct JobAccepted {
+    job_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PresetReport {
+    job_id: Option<String>,
+    image_id: u64,
+    preset: String,
+    tasks: Vec<TaskReport>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    request_id: &'a str,
+}
+
+fn error_response(e: &HttpError, request_id: &str) -> Response {
+    let body = ErrorBody {
+        code: e.code(),
+        message: e.to_string(),
+        request_id: request_id,
+    };
+    json_response(e.status(), &body)
+}
+
+/// Sends a single task result as is, and several as multipart/mixed parts
+fn image_response(tasks: Vec<TaskReport>) -> Result<Response, HttpError> {
+    let mut images = Vec::new();
+    for task in tasks {
+        match task {
+            TaskReport { image: Some(image), task, .. } => images.push((task, image)),
+            // sources which cannot be used are the client's fault
+            TaskReport { failure: Some(e @ JobError::Image(_)), .. } |
+            TaskReport { failure: Some(e @ JobError::TooLarge(..)), .. } => {
+                return Err(HttpError::Job(e));
+            }
+            TaskReport { task, error, .. } => {
+                let error = error.unwrap_or_else(|| "no image produced".to_string());
+                return Err(HttpError::TaskFailed(task, error));
+            }
+        }
+    }
+    if images.is_empty() {
+        return Err(HttpError::NoResult);
+    }
+
+    if images.len() == 1 {
+        let (_, image) = images.pop().unwrap();
+        return Ok(Response::new()
+                      .with_header(image_content_type(&image))
+                      .with_header(ContentLength(image.bytes.len() as u64))
+                      .with_body(image.bytes));
+    }
+
+    let boundary = format!("gravure-{}", request_id());
+    let mut body = Vec::new();
+    for (task, image) in images {
+        write!(body,
+               "--{}\r\nContent-Type: {}\r\nContent-Disposition: attachment; \
+                filename=\"{}.{}\"\r\n\r\n",
+               boundary,
+               image_content_type(&image),
+               task,
+               image.extension)
+                .map_err(|e| HttpError::Io(e))?;
+        body.extend_from_slice(&image.bytes);
+        body.extend_from_slice(b"\r\n");
+    }
+    write!(body, "--{}--\r\n", boundary).map_err(|e| HttpError::Io(e))?;
+
+    let content_type = format!("multipart/mixed; boundary={}", boundary);
+    let mut response = Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body);
+    response.headers_mut().set_raw("Content-Type", content_type);
+    Ok(response)
+}
+
+fn image_content_type(image: &EncodedImage) -> ContentType {
+    match image.format {
+        ImageFormat::PNG => ContentType::png(),
+        ImageFormat::JPEG => ContentType::jpeg(),
+        _ => ContentType::octet_stream(),
+    }
+}
+
+/// Posts a report to its callback url in background, failures are only logged
+fn send_callback<T: Serialize>(handle: &Handle, config: &Config, url: &str, report: &T) {
+    let body = match serde_json::to_vec(report) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("could not serialize callback report {}", e);
+            return;
+        }
+    };
+    let sent = url.to_string();
+    let failed = url.to_string();
+    let post = callback::post(handle, &config.outbound, &config.callbacks, url, body)
+        .map(move |_| info!("callback sent"; "url"=>sent))
+        .map_err(move |e| warn!("callback failed {}", e; "url"=>failed));
+    handle.spawn(post);
+}
+
+/// Callback url from `X-Callback-Url` header or `callback` query parameter
+fn callback_requested(req: &Request) -> Option<String> {
+    if let Some(url) = header_value(req, CALLBACK_HEADER) {
+        return Some(url);
+    }
+    req.query().and_then(|query| {
+                             form_urlencoded::parse(query.as_bytes())
+                                 .find(|&(ref key, _)| key == "callback")
+                                 .map(|(_, value)| value.into_owned())
+                         })
+}
+
+fn header_value(req: &Request, name: &str) -> Option<String> {
+    req.headers()
+        .get_raw(name)
+        .and_then(|raw| raw.one())
+        .and_then(|value| String::from_utf8(value.to_vec()).ok())
+}
+
+/// Checks `sync` query parameter, which makes the response wait until every task has finished
+fn sync_requested(req: &Request) -> bool {
+    req.query()
+        .map(|query| {
+                 form_urlencoded::parse(query.as_bytes())
+                     .any(|(key, value)| key == "sync" && value != "0" && value != "false")
+             })
+        .unwrap_or(false)
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response {
+    let body = serde_json::to_string(body).unwrap_or_default();
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::json())
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+/// Generates an id to find a request in logs by
+fn request_id() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut hasher = DefaultHasher::default();
+    COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
 impl Service for GravureServer {
     type Request = Request;
     type Response = Response;
@@ -155,21 +1009,32 @@ impl Service for GravureServer {
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        let mut resp = Response::new();
-
         let client_log = match req.remote_addr() {
             Some(addr) => format!("{}", addr),
             None => "unknown".to_string(),
         };
+        let request_id = request_id();
+        let retry_after = self.config.backlog.retry_after;
         slog_scope::scope(&slog_scope::logger()
-                                   .new(slog_o!("scope" => "request handler", "client"=>client_log)),
+                                   .new(slog_o!("scope" => "request handler", "client"=>client_log, "request_id"=>request_id.clone())),
                                    || {
-        if let Err(_e) = self.route(req) {
-            info!("HTTP server error: {}", _e);
-            resp.set_status(StatusCode::BadRequest);
-        }
+        let response = self.route(req).then(move |result| {
+            let mut resp = match result {
+                Ok(resp) => resp,
+                Err(e) => {
+                    info!("HTTP server error: {}", e; "request_id"=>request_id.clone());
+                    let mut resp = error_response(&e, &request_id);
+                    if let HttpError::Overloaded = e {
+                        resp.headers_mut().set_raw("Retry-After", retry_after.to_string());
+                    }
+                    resp
+                }
+            };
+            resp.headers_mut().set_raw("X-Request-Id", request_id);
+            Ok::<_, hyper::Error>(resp)
+        });
 
-        Box::new(ok(resp))
+        Box::new(response)
                                    })
     }
 }
@@ -0,0 +1,137 @@
+use config::Signing;
+use errors::SignatureError;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hex;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Query parameter with hex encoded HMAC-SHA256 of the path and the rest of the query
+pub const SIGNATURE_PARAM: &'static str = "signature";
+/// Query parameter with unix time after which a signed url is not valid anymore
+pub const EXPIRES_PARAM: &'static str = "expires";
+
+/// Signs `path` with `query` as it is sent, without the signature parameter
+pub fn sign(key: &str, path: &str, query: &str) -> String {
+    hex::encode(mac(key, path, query).result().code())
+}
+
+/// Hex encoded HMAC-SHA256 of a request body
+pub fn sign_body(key: &str, body: &[u8]) -> String {
+    hex::encode(hmac(key.as_bytes(), body))
+}
+
+/// HMAC-SHA256 of `data` keyed with `key`
+pub fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = keyed_mac(key);
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// HMAC-SHA256 to be fed data piece by piece
+pub fn keyed_mac(key: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes keys of any length, so this cannot fail
+    Hmac::<Sha256>::new(key).unwrap()
+}
+
+pub fn verify(signing: &Signing, path: &str, query: Option<&str>) -> Result<(), SignatureError> {
+    let mut signature = None;
+    let mut expires = None;
+    let mut signed = Vec::new();
+    for pair in query.unwrap_or("").split('&').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(SIGNATURE_PARAM), value) => signature = value,
+            (Some(EXPIRES_PARAM), value) => {
+                expires = value;
+                signed.push(pair);
+            }
+            _ => signed.push(pair),
+        }
+    }
+
+    let signature = signature.ok_or(SignatureError::Missing)?;
+    let signature = hex::decode(signature).map_err(|_| SignatureError::Invalid)?;
+    let signed = signed.join("&");
+    // every configured key is valid, so keys can be rotated without breaking issued urls
+    let valid = signing
+        .keys
+        .iter()
+        .any(|key| mac(key, path, &signed).verify(&signature).is_ok());
+    if !valid {
+        return Err(SignatureError::Invalid);
+    }
+
+    // expiry is only trusted once it is known to be signed
+    match expires {
+        Some(expires) => {
+            let expires: u64 = expires.parse().map_err(|_| SignatureError::Invalid)?;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0);
+            if now > expires {
+                return Err(SignatureError::Expired);
+            }
+        }
+        None if signing.require_expiry => return Err(SignatureError::Missing),
+        None => (),
+    }
+    Ok(())
+}
+
+fn mac(key: &str, path: &str, query: &str) -> Hmac<Sha256> {
+    let mut mac = keyed_mac(key.as_bytes());
+    mac.input(path.as_bytes());
+    if !query.is_empty() {
+        mac.input(b"?");
+        mac.input(query.as_bytes());
+    }
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[test]
+    fn test_verify() {
+        let signing = Signing {
+            keys: vec!["old".to_owned(), "new".to_owned()],
+            require_expiry: false,
+        };
+        let path = "/v1/img/preset1/1234";
+
+        let signature = sign("old", path, "");
+        let query = format!("signature={}", signature);
+        assert!(verify(&signing, path, Some(&query)).is_ok());
+        assert!(verify(&signing, "/v1/img/preset1/1235", Some(&query)).is_err());
+
+        let signature = sign("new", path, "expires=4102444800");
+        let query = format!("expires=4102444800&signature={}", signature);
+        assert!(verify(&signing, path, Some(&query)).is_ok());
+
+        let query = format!("expires=4102444801&signature={}", signature);
+        match verify(&signing, path, Some(&query)) {
+            Err(SignatureError::Invalid) => (),
+            _ => panic!("tampered expiry accepted"),
+        }
+
+        let signature = sign("new", path, "expires=1000");
+        let query = format!("expires=1000&signature={}", signature);
+        match verify(&signing, path, Some(&query)) {
+            Err(SignatureError::Expired) => (),
+            _ => panic!("expired url accepted"),
+        }
+
+        match verify(&signing, path, None) {
+            Err(SignatureError::Missing) => (),
+            _ => panic!("unsigned url accepted"),
+        }
+
+        // RFC 4231 test case 2
+        assert_eq!(sign_body("Jefe", b"what do ya want for nothing?"),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+}
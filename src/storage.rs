@@ -0,0 +1,762 @@
+use actions::EncodedImage;
+use config::{OnExists, OutboundConfig, S3Config, StorageConfig};
+use errors::{ActionError, OutboundError};
+use form;
+use outbound::{self, OutboundFuture, Slot};
+use s3;
+use template::PathTemplate;
+
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, File, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::Future;
+use futures::future::{err, ok};
+use hyper::{Method, StatusCode, Uri};
+use hyper::client::{Request, Response};
+use hyper::header::{Authorization, Basic, ContentLength};
+use tokio_core::reactor::{Handle, Remote as Sender};
+
+/// Place encoded results are written to, at paths rendered by actions
+pub trait Storage: Send + Sync {
+    /// Writes `image` of image `id` at `path` relative to the storage root, telling whether
+    /// it did, as a storage may keep what is there already
+    fn store(&self, id: u64, path: &str, image: &EncodedImage) -> Result<bool, ActionError>;
+
+    /// Deletes what was stored at `path` for image `id` with file `extension`,
+    /// telling whether there was anything
+    fn remove(&self, id: u64, extension: &str, path: &str) -> Result<bool, ActionError>;
+}
+
+pub fn build(config: &StorageConfig,
+             executor: &Sender,
+             outbound: &Arc<OutboundConfig>)
+             -> Result<Arc<Storage>, ActionError> {
+    let storage: Arc<Storage> = match config {
+        &StorageConfig::Local {
+            ref root,
+            ref file_mode,
+            ref dir_mode,
+            on_exists,
+        } => {
+            let mut storage = LocalStorage::new(root).on_exists(on_exists);
+            if let &Some(ref mode) = file_mode {
+                storage = storage.file_mode(parse_mode(mode)?);
+            }
+            if let &Some(ref mode) = dir_mode {
+                storage = storage.dir_mode(parse_mode(mode)?);
+            }
+            Arc::new(storage)
+        }
+        &StorageConfig::Http {
+            ref url,
+            ref method,
+            multipart,
+            ref field,
+            ref headers,
+        } => {
+            let method = match method.as_str() {
+                "POST" => Method::Post,
+                "PUT" => Method::Put,
+                _ => return Err(ActionError::Parameter),
+            };
+            let headers = headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            let multipart_field = if multipart { Some(field.clone()) } else { None };
+            Arc::new(HttpStorage::new(url.clone(),
+                                      method,
+                                      multipart_field,
+                                      headers,
+                                      executor.clone(),
+                                      outbound.clone())?)
+        }
+        &StorageConfig::Webdav {
+            ref url,
+            ref username,
+            ref password,
+        } => {
+            let credentials = username
+                .as_ref()
+                .map(|username| {
+                         Basic {
+                             username: username.clone(),
+                             password: password.clone(),
+                         }
+                     });
+            Arc::new(WebDavStorage {
+                         url: url.clone(),
+                         credentials: credentials,
+                         executor: executor.clone(),
+                         outbound: outbound.clone(),
+                     })
+        }
+        &StorageConfig::S3(ref config) => {
+            Arc::new(S3Storage::new(Arc::new(config.clone()), executor.clone(), outbound.clone()))
+        }
+        &StorageConfig::Memory => Arc::new(MemoryStorage::default()),
+    };
+    Ok(storage)
+}
+
+/// Makes names of temporary files unique among concurrent writes
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Files under a root directory, which rendered paths cannot leave. They are written aside
+/// and renamed into place, so readers never see partially written files.
+pub struct LocalStorage {
+    root: PathBuf,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    on_exists: OnExists,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> Self {
+        LocalStorage {
+            root: PathBuf::from(root),
+            file_mode: None,
+            dir_mode: None,
+            on_exists: OnExists::Overwrite,
+        }
+    }
+
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = Some(mode);
+        self
+    }
+
+    /// Mode of created directories, umask applies to it
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.dir_mode = Some(mode);
+        self
+    }
+
+    pub fn on_exists(mut self, on_exists: OnExists) -> Self {
+        self.on_exists = on_exists;
+        self
+    }
+
+    fn write(&self, temp: &Path, path: &Path, image: &EncodedImage) -> io::Result<bool> {
+        {
+            let mut file = OpenOptions::new().write(true).create_new(true).open(temp)?;
+            if let Some(mode) = self.file_mode {
+                file.set_permissions(Permissions::from_mode(mode))?;
+            }
+            file.write_all(&image.bytes)?;
+            file.sync_all()?;
+        }
+        match self.on_exists {
+            OnExists::Overwrite => fs::rename(temp, path)?,
+            // linking fails on existing files, unlike renaming
+            _ => {
+                let linked = fs::hard_link(temp, path);
+                fs::remove_file(temp)?;
+                match linked {
+                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+                    linked => linked?,
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn store(&self, _id: u64, path: &str, image: &EncodedImage) -> Result<bool, ActionError> {
+        let path = resolve(&self.root, path)?;
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let name = path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        if let Some(mode) = self.dir_mode {
+            builder.mode(mode);
+        }
+        let confined = self.root != Path::new("");
+        if confined && !self.root.is_dir() {
+            builder.create(&self.root).map_err(|e| ActionError::Io(e))?;
+        }
+        // symlinks may lead out of the root as well, so the part of the path which exists
+        // is checked before any directory is created under it
+        let escapes = |dir: &Path| -> Result<bool, ActionError> {
+            let root = self.root.canonicalize().map_err(|e| ActionError::Io(e))?;
+            let real = existing_ancestor(dir)
+                .canonicalize()
+                .map_err(|e| ActionError::Io(e))?;
+            Ok(!real.starts_with(&root))
+        };
+        if confined && escapes(&parent)? {
+            return Err(ActionError::PathEscapesRoot(path.to_string_lossy().into_owned()));
+        }
+        if !parent.is_dir() {
+            builder.create(&parent).map_err(|e| ActionError::Io(e))?;
+            // a symlink may have been put in place meanwhile
+            if confined && escapes(&parent)? {
+                return Err(ActionError::PathEscapesRoot(path.to_string_lossy().into_owned()));
+            }
+        }
+
+        info!("SAVING to {:?}", path);
+        let temp = parent.join(format!(".{}.{}.{}.tmp",
+                                       name,
+                                       process::id(),
+                                       TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        match self.write(&temp, &path, image) {
+            Ok(true) => {
+                // the rename itself is durable once the directory is synced
+                File::open(&parent).and_then(|dir| dir.sync_all()).ok();
+                Ok(true)
+            }
+            Ok(false) if self.on_exists == OnExists::Skip => {
+                debug!("file exists, not saved"; "path"=>format!("{:?}", path));
+                Ok(false)
+            }
+            Ok(false) => Err(ActionError::Exists(path.to_string_lossy().into_owned())),
+            Err(e) => {
+                fs::remove_file(&temp).ok();
+                Err(ActionError::Io(e))
+            }
+        }
+    }
+
+    fn remove(&self, _id: u64, _extension: &str, path: &str) -> Result<bool, ActionError> {
+        let path = resolve(&self.root, path)?;
+        match fs::remove_file(&path) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(ActionError::Io(e)),
+        }
+    }
+}
+
+/// Deepest ancestor of `dir` which exists, `dir` itself if it does
+fn existing_ancestor(dir: &Path) -> &Path {
+    let mut existing = dir;
+    while !existing.exists() {
+        match existing.parent() {
+            Some(parent) if parent != Path::new("") => existing = parent,
+            _ => return Path::new("."),
+        }
+    }
+    existing
+}
+
+/// Joins `path` to `root`, resolving `.` and `..` without leaving the root. Absolute paths are
+/// taken relative to the root, unless there is none.
+pub fn resolve(root: &Path, path: &str) -> Result<PathBuf, ActionError> {
+    let mut resolved = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Prefix(_) |
+            Component::RootDir => {
+                if root == Path::new("") {
+                    resolved.push(component.as_os_str());
+                }
+            }
+            Component::CurDir => (),
+            Component::ParentDir => {
+                let parent = resolved.parent().map(|parent| parent.to_path_buf());
+                match parent {
+                    Some(parent) => resolved = parent,
+                    None => return Err(ActionError::PathEscapesRoot(path.to_string())),
+                }
+            }
+            Component::Normal(name) => resolved.push(name),
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+/// Parses octal file modes like `"644"`
+pub fn parse_mode(mode: &str) -> Result<u32, ActionError> {
+    u32::from_str_radix(mode, 8).map_err(|_| ActionError::Parameter)
+}
+
+/// Requests carrying the image to urls made of the storage url and the path
+pub struct HttpStorage {
+    url: String,
+    method: Method,
+    /// Form field name when the image is sent as `multipart/form-data`
+    multipart_field: Option<String>,
+    /// Header names with value templates
+    headers: Vec<(String, String)>,
+    executor: Sender,
+    outbound: Arc<OutboundConfig>,
+}
+
+impl HttpStorage {
+    pub fn new(url: String,
+               method: Method,
+               multipart_field: Option<String>,
+               headers: Vec<(String, String)>,
+               executor: Sender,
+               outbound: Arc<OutboundConfig>)
+               -> Result<Self, ActionError> {
+        for &(_, ref value) in &headers {
+            try!(PathTemplate::new(value.clone()).map_err(|_| ActionError::Parameter));
+        }
+        Ok(HttpStorage {
+               url: url,
+               method: method,
+               multipart_field: multipart_field,
+               headers: headers,
+               executor: executor,
+               outbound: outbound,
+           })
+    }
+}
+
+impl Storage for HttpStorage {
+    fn store(&self, id: u64, path: &str, image: &EncodedImage) -> Result<bool, ActionError> {
+        let uri = parse_uri(&format!("{}{}", self.url, path))?;
+        outbound::check_uri(&self.outbound, &uri).map_err(|e| ActionError::Outbound(e))?;
+        let uri_log = format!("{:?}", &uri);
+
+        let headers = self.render_headers(id, image.extension)?;
+        let (content_type, body) = match self.multipart_field {
+            Some(ref field) => {
+                let boundary = form::new_boundary(&image.bytes);
+                let filename = format!("{}.{}", id, image.extension);
+                let body = form::encode_file(&boundary,
+                                             field,
+                                             &filename,
+                                             image.content_type(),
+                                             &image.bytes);
+                (format!("multipart/form-data; boundary={}", boundary), body)
+            }
+            None => (image.content_type().to_string(), image.bytes.clone()),
+        };
+        self.send(self.method.clone(), uri, headers, Some(content_type), body)
+            .map_err(|e| ActionError::Outbound(e))?;
+        debug!("external upload successful"; "uri"=>uri_log);
+        Ok(true)
+    }
+
+    fn remove(&self, id: u64, extension: &str, path: &str) -> Result<bool, ActionError> {
+        let uri = parse_uri(&format!("{}{}", self.url, path))?;
+        outbound::check_uri(&self.outbound, &uri).map_err(|e| ActionError::Outbound(e))?;
+        let headers = self.render_headers(id, extension)?;
+        match self.send(Method::Delete, uri, headers, None, Vec::new()) {
+            Ok(_) => Ok(true),
+            Err(ref e) if is_missing(e) => Ok(false),
+            Err(e) => Err(ActionError::Outbound(e)),
+        }
+    }
+}
+
+impl HttpStorage {
+    fn render_headers(&self,
+                      id: u64,
+                      extension: &str)
+                      -> Result<Vec<(String, String)>, ActionError> {
+        let mut headers = Vec::new();
+        for &(ref name, ref value) in &self.headers {
+            let value = PathTemplate::new(value.clone())
+                .and_then(|template| template.render(id, extension.to_owned()))
+                .map_err(|e| ActionError::BadTemplate(e))?;
+            headers.push((name.clone(), value));
+        }
+        Ok(headers)
+    }
+
+    fn send(&self,
+            method: Method,
+            uri: Uri,
+            headers: Vec<(String, String)>,
+            content_type: Option<String>,
+            body: Vec<u8>)
+            -> Result<(), OutboundError> {
+        let outbound = self.outbound.clone();
+        outbound::block_on(&self.executor, move |handle| {
+            let request = move || {
+                let mut request = Request::new(method.clone(), uri.clone());
+                if let Some(ref content_type) = content_type {
+                    request
+                        .headers_mut()
+                        .set_raw("Content-Type", content_type.clone());
+                }
+                request.headers_mut().set(ContentLength(body.len() as u64));
+                for &(ref name, ref value) in &headers {
+                    request.headers_mut().set_raw(name.clone(), value.clone());
+                }
+                request.set_body(body.clone());
+                request
+            };
+            Box::new(outbound::send_with_retries(handle, &outbound, request).map(|_| ()))
+        })
+    }
+}
+
+/// Responses telling there is nothing at the url
+fn is_missing(e: &OutboundError) -> bool {
+    match e {
+        &OutboundError::Status(StatusCode::NotFound) |
+        &OutboundError::Status(StatusCode::Gone) => true,
+        _ => false,
+    }
+}
+
+/// WebDAV collection, missing collections on the path are created
+pub struct WebDavStorage {
+    url: String,
+    credentials: Option<Basic>,
+    executor: Sender,
+    outbound: Arc<OutboundConfig>,
+}
+
+impl Storage for WebDavStorage {
+    fn store(&self, _id: u64, path: &str, image: &EncodedImage) -> Result<bool, ActionError> {
+        let uri = parse_uri(&format!("{}{}", self.url, path))?;
+        outbound::check_uri(&self.outbound, &uri).map_err(|e| ActionError::Outbound(e))?;
+        // parent collections, outermost first
+        let mut collections = Vec::new();
+        let mut parent = String::new();
+        let segments = path.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>();
+        for segment in segments.iter().take(segments.len().saturating_sub(1)) {
+            parent.push_str(segment);
+            parent.push('/');
+            collections.push(parse_uri(&format!("{}{}", self.url, parent))?);
+        }
+
+        let credentials = self.credentials.clone();
+        let outbound = self.outbound.clone();
+        let content_type = image.content_type();
+        let body = image.bytes.clone();
+        let stored = outbound::block_on(&self.executor, move |handle| {
+            let dav = Dav {
+                handle: handle.clone(),
+                outbound: outbound,
+                credentials: credentials,
+            };
+            let retry = dav.clone();
+            let put = dav.send(Method::Put, uri.clone(), Some(content_type), body.clone())
+                .map(|_| ())
+                .or_else(move |e| match e {
+                    // the parent collection is missing
+                    OutboundError::Status(StatusCode::Conflict) => {
+                        let put = retry
+                            .make_collections(collections)
+                            .and_then(move |_| {
+                                          retry.send(Method::Put, uri, Some(content_type), body)
+                                      })
+                            .map(|_| ());
+                        Box::new(put) as OutboundFuture<()>
+                    }
+                    e => Box::new(err(e)),
+                });
+            Box::new(put)
+        });
+        stored.map_err(|e| ActionError::Outbound(e))?;
+        Ok(true)
+    }
+
+    fn remove(&self, _id: u64, _extension: &str, path: &str) -> Result<bool, ActionError> {
+        let uri = parse_uri(&format!("{}{}", self.url, path))?;
+        outbound::check_uri(&self.outbound, &uri).map_err(|e| ActionError::Outbound(e))?;
+        let credentials = self.credentials.clone();
+        let outbound = self.outbound.clone();
+        let removed = outbound::block_on(&self.executor, move |handle| {
+            let dav = Dav {
+                handle: handle.clone(),
+                outbound: outbound,
+                credentials: credentials,
+            };
+            Box::new(dav.send(Method::Delete, uri, None, Vec::new()).map(|_| ()))
+        });
+        match removed {
+            Ok(_) => Ok(true),
+            Err(ref e) if is_missing(e) => Ok(false),
+            Err(e) => Err(ActionError::Outbound(e)),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Dav {
+    handle: Handle,
+    outbound: Arc<OutboundConfig>,
+    credentials: Option<Basic>,
+}
+
+impl Dav {
+    fn send(&self,
+            method: Method,
+            uri: Uri,
+            content_type: Option<&'static str>,
+            body: Vec<u8>)
+            -> OutboundFuture<(Response, Slot)> {
+        let credentials = self.credentials.clone();
+        let request = move || {
+            let mut request = Request::new(method.clone(), uri.clone());
+            if let Some(ref credentials) = credentials {
+                request
+                    .headers_mut()
+                    .set(Authorization(credentials.clone()));
+            }
+            if let Some(content_type) = content_type {
+                request.headers_mut().set_raw("Content-Type", content_type);
+            }
+            request.headers_mut().set(ContentLength(body.len() as u64));
+            request.set_body(body.clone());
+            request
+        };
+        outbound::send_with_retries(&self.handle, &self.outbound, request)
+    }
+
+    /// Makes collections one by one, existing ones are fine
+    fn make_collections(&self, collections: Vec<Uri>) -> OutboundFuture<()> {
+        let dav = self.clone();
+        let done = Box::new(ok(())) as OutboundFuture<()>;
+        collections
+            .into_iter()
+            .fold(done, move |done, collection| {
+                let dav = dav.clone();
+                let made = done.and_then(move |_| {
+                    dav.send(Method::Extension("MKCOL".to_string()), collection, None, Vec::new())
+                        .then(|result| match result {
+                                  Err(OutboundError::Status(StatusCode::MethodNotAllowed)) |
+                                  Ok(_) => Ok(()),
+                                  Err(e) => Err(e),
+                              })
+                });
+                Box::new(made) as OutboundFuture<()>
+            })
+    }
+}
+
+/// Objects of an S3 compatible bucket, keyed by paths
+pub struct S3Storage {
+    config: Arc<S3Config>,
+    bucket: String,
+    content_type: Option<String>,
+    cache_control: Option<String>,
+    executor: Sender,
+    outbound: Arc<OutboundConfig>,
+}
+
+impl S3Storage {
+    pub fn new(config: Arc<S3Config>, executor: Sender, outbound: Arc<OutboundConfig>) -> Self {
+        S3Storage {
+            bucket: config.bucket.clone(),
+            config: config,
+            content_type: None,
+            cache_control: None,
+            executor: executor,
+            outbound: outbound,
+        }
+    }
+
+    pub fn bucket(mut self, bucket: String) -> Self {
+        self.bucket = bucket;
+        self
+    }
+
+    /// Content type stored instead of the one of the output format
+    pub fn content_type(mut self, content_type: String) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    pub fn cache_control(mut self, cache_control: String) -> Self {
+        self.cache_control = Some(cache_control);
+        self
+    }
+}
+
+impl Storage for S3Storage {
+    fn store(&self, _id: u64, path: &str, image: &EncodedImage) -> Result<bool, ActionError> {
+        let content_type = self.content_type
+            .clone()
+            .unwrap_or_else(|| image.content_type().to_string());
+        let mut headers = vec![("Content-Type".to_string(), content_type)];
+        if let Some(ref cache_control) = self.cache_control {
+            headers.push(("Cache-Control".to_string(), cache_control.clone()));
+        }
+        let object = s3::Object {
+            bucket: self.bucket.clone(),
+            // keys are relative to the bucket
+            key: path.trim_start_matches('/').to_string(),
+            headers: headers,
+            body: image.bytes.clone(),
+        };
+
+        let outbound = self.outbound.clone();
+        let config = self.config.clone();
+        let stored = outbound::block_on(&self.executor,
+                                        move |handle| s3::put(handle, outbound, config, object));
+        stored.map_err(|e| ActionError::Outbound(e))?;
+        debug!("saved to s3"; "path"=>path);
+        Ok(true)
+    }
+
+    /// S3 does not tell whether the object existed, so it is always reported removed
+    fn remove(&self, _id: u64, _extension: &str, path: &str) -> Result<bool, ActionError> {
+        let outbound = self.outbound.clone();
+        let config = self.config.clone();
+        let bucket = self.bucket.clone();
+        let key = path.trim_start_matches('/').to_string();
+        let removed = outbound::block_on(&self.executor, move |handle| {
+            s3::delete(handle, outbound, config, bucket, key)
+        });
+        removed.map_err(|e| ActionError::Outbound(e))?;
+        Ok(true)
+    }
+}
+
+/// Images kept in memory, for tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    images: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.images.lock().unwrap().get(path).cloned()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn store(&self, _id: u64, path: &str, image: &EncodedImage) -> Result<bool, ActionError> {
+        self.images
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), image.bytes.clone());
+        Ok(true)
+    }
+
+    fn remove(&self, _id: u64, _extension: &str, path: &str) -> Result<bool, ActionError> {
+        Ok(self.images.lock().unwrap().remove(path).is_some())
+    }
+}
+
+fn parse_uri(url: &str) -> Result<Uri, ActionError> {
+    Uri::from_str(url).map_err(|e| ActionError::UrlParse(e))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use actions::{Action, ActionContext, ImageData};
+    use image::{DynamicImage, ImageFormat};
+    use std::env;
+    use std::io::Read;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_store() {
+        let core = Core::new().unwrap();
+        let memory = Arc::new(MemoryStorage::default());
+        let mut context = ActionContext {
+            executor: core.remote(),
+            outbound: Arc::new(OutboundConfig::default()),
+            s3: None,
+            storages: HashMap::new(),
+            save_root: None,
+        };
+        context
+            .storages
+            .insert("memory".to_string(), memory.clone());
+
+        let params = vec!["store".to_string(),
+                          "memory".to_string(),
+                          "images/{{image_id}}.{{ext}}".to_string()];
+        let action = Action::from_params(&params, &context).unwrap();
+        let mut image_data = ImageData {
+            image: DynamicImage::new_rgb8(4, 4),
+            image_format: ImageFormat::PNG,
+            id: 7,
+        };
+        action.run(&mut image_data, None).unwrap();
+        let stored = memory.get("images/7.png").unwrap();
+        assert_eq!(&stored[1..4], b"PNG");
+
+        let removed = action.purge(7);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(removed[0].0, "images/7.jpg");
+        assert_eq!(removed[0].1.as_ref().ok(), Some(&false));
+        assert_eq!(removed[1].0, "images/7.png");
+        assert_eq!(removed[1].1.as_ref().ok(), Some(&true));
+        assert!(memory.get("images/7.png").is_none());
+
+        let params = vec!["store".to_string(), "missing".to_string(), "{{image_id}}".to_string()];
+        assert!(Action::from_params(&params, &context).is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let root = Path::new("/srv/images");
+        assert_eq!(resolve(root, "a/./b/../c.png").unwrap(),
+                   Path::new("/srv/images/a/c.png"));
+        assert_eq!(resolve(root, "/a/c.png").unwrap(),
+                   Path::new("/srv/images/a/c.png"));
+        assert!(resolve(root, "a/../../c.png").is_err());
+        assert!(resolve(root, "../images/c.png").is_err());
+        assert_eq!(resolve(Path::new(""), "/tmp/c.png").unwrap(),
+                   Path::new("/tmp/c.png"));
+        assert!(resolve(Path::new(""), "/tmp/../../c.png").is_err());
+    }
+
+    #[test]
+    fn test_local_store() {
+        let root = env::temp_dir().join(format!("gravure-test-{}", process::id()));
+        let image = |bytes: &[u8]| {
+            EncodedImage {
+                format: ImageFormat::PNG,
+                extension: "png",
+                bytes: bytes.to_vec(),
+            }
+        };
+        let read = |path: &str| {
+            let mut bytes = Vec::new();
+            File::open(root.join(path))
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .unwrap();
+            bytes
+        };
+
+        let storage = LocalStorage::new(root.to_str().unwrap()).file_mode(0o600);
+        storage.store(1, "a/b/1.png", &image(b"first")).unwrap();
+        assert_eq!(read("a/b/1.png"), b"first");
+        let mode = fs::metadata(root.join("a/b/1.png")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        storage.store(1, "a/b/1.png", &image(b"second")).unwrap();
+        assert_eq!(read("a/b/1.png"), b"second");
+
+        let storage = LocalStorage::new(root.to_str().unwrap()).on_exists(OnExists::Skip);
+        assert!(!storage.store(1, "a/b/1.png", &image(b"third")).unwrap());
+        assert_eq!(read("a/b/1.png"), b"second");
+
+        let storage = LocalStorage::new(root.to_str().unwrap()).on_exists(OnExists::Fail);
+        assert!(storage.store(1, "a/b/1.png", &image(b"third")).is_err());
+        storage.store(2, "a/b/2.png", &image(b"fourth")).unwrap();
+        assert_eq!(read("a/b/2.png"), b"fourth");
+        // no temporary files are left
+        assert_eq!(fs::read_dir(root.join("a/b")).unwrap().count(), 2);
+
+        // nothing is created through a symlink leading out of the root
+        let outside = env::temp_dir().join(format!("gravure-test-outside-{}", process::id()));
+        fs::create_dir_all(&outside).unwrap();
+        ::std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        match storage.store(3, "link/c/3.png", &image(b"fifth")) {
+            Err(ActionError::PathEscapesRoot(_)) => (),
+            _ => panic!("symlink out of the root followed"),
+        }
+        assert!(!outside.join("c").exists());
+
+        fs::remove_dir_all(&outside).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
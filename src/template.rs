@@ -14,16 +14,28 @@ impl PathTemplate {
     }
 
     pub fn render(&self, id: u64, ext: String) -> Result<String, TemplateError> {
-        let id_str = id.to_string();
-        let node_id: String = id_str.clone().chars().take(2).collect();
         let ext = match ext.as_str() {
             "png" | "jpg" => ext,
             _ => return Err(TemplateError::Convert),
         };
-        let mut context = Context::new();
-        context.set_val("node_id", Value::Str(node_id.to_owned()));
-        context.set_val("image_id", Value::Str(id_str));
+        let mut context = id_context(id);
         context.set_val("ext", Value::Str(ext));
+        self.render_context(context)
+    }
+
+    /// Renders task `url_template`s, which know the task name instead of the extension
+    pub fn render_url(&self, id: u64, task_name: &str) -> Result<String, TemplateError> {
+        let mut context = id_context(id);
+        context.set_val("task_name", Value::Str(task_name.to_owned()));
+        self.render_context(context)
+    }
+
+    /// Renders templates which depend on the image id only, like origin paths
+    pub fn render_id(&self, id: u64) -> Result<String, TemplateError> {
+        self.render_context(id_context(id))
+    }
+
+    fn render_context(&self, mut context: Context) -> Result<String, TemplateError> {
         let result = try!(self.template
                               .render(&mut context)
                               .map_err(|e| TemplateError::Engine(e)));
@@ -31,6 +43,15 @@ impl PathTemplate {
     }
 }
 
+fn id_context(id: u64) -> Context {
+    let id_str = id.to_string();
+    let node_id: String = id_str.clone().chars().take(2).collect();
+    let mut context = Context::new();
+    context.set_val("node_id", Value::Str(node_id));
+    context.set_val("image_id", Value::Str(id_str));
+    context
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -45,4 +66,15 @@ mod tests {
 
         assert_eq!(res, "http://12.localhost/qwer/1234.png");
     }
+
+    #[test]
+    fn test_url_conv() {
+        let url = PathTemplate::new("http://{{node_id}}/protools/{{task_name}}/{{image_id}}"
+                                        .to_owned())
+                .unwrap();
+
+        let res = url.render_url(1234, "task1").unwrap();
+
+        assert_eq!(res, "http://12/protools/task1/1234");
+    }
 }